@@ -5,14 +5,15 @@ use std::env::var;
 async fn main() {
     let email = var("HELPER_EMAIL").unwrap();
     let password = var("HELPER_PASSWORD").unwrap();
-    let f = SyncClient::new(&email, &password).await;
+    let f = SyncClient::new(&email, &password).await.unwrap();
     //let logins = f.get_logins().await;
-    let all_passwords = f.get_collection("passwords").await;
+    let all_passwords = f.get_collection("passwords").await.unwrap();
     f.delete_objects(
         &all_passwords
             .iter()
             .map(|password| password.as_str())
             .collect::<Vec<_>>(),
     )
-    .await;
+    .await
+    .unwrap();
 }
@@ -6,10 +6,13 @@ use std::env::var;
 async fn main() {
     let email = var("HELPER_EMAIL").unwrap();
     let password = var("HELPER_PASSWORD").unwrap();
-    let f = SyncClient::new(&email, &password).await;
-    let logins = f.get_collection("passwords").await;
+    let f = SyncClient::new(&email, &password).await.unwrap();
+    let logins = f.get_collection("passwords").await.unwrap();
     for login in logins {
-        let object: Value = f.get_storage_object(format!("passwords/{}", login)).await;
+        let object: Value = f
+            .get_storage_object(format!("passwords/{}", login))
+            .await
+            .unwrap();
         println!("{}", object);
     }
 }
@@ -0,0 +1,242 @@
+//! Records of the `addresses` and `creditcards` collections, which Firefox
+//! uses to fill forms.
+//!
+//! Firefox keeps credit card numbers encrypted again with a key of the device
+//! in `cc-number-enc`. That key never leaves the device, so the number is
+//! synced in `cc-number`, only protected by the collection key like the rest
+//! of the record.
+
+use secstr::SecUtf8;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    generate_bso_id, unix_time_millis, BsoObject, Collection, Result, SyncClient, UploadReport,
+};
+
+/// Version of the address records Firefox writes
+const ADDRESS_VERSION: u64 = 1;
+/// Version of the credit card records Firefox writes
+const CREDIT_CARD_VERSION: u64 = 3;
+
+type UnknownFields = serde_json::Map<String, serde_json::Value>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Address {
+    id: String,
+    pub entry: AddressEntry,
+}
+
+/// The fields of an address, named like the `autocomplete` attributes of the
+/// form fields they fill.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct AddressEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub given_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub additional_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub family_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub organization: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub street_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address_level3: Option<String>,
+    /// City or town
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address_level2: Option<String>,
+    /// State, province or region
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address_level1: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub postal_code: Option<String>,
+    /// ISO 3166 country code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tel: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(rename = "timeCreated", skip_serializing_if = "Option::is_none")]
+    pub time_created: Option<u64>,
+    #[serde(rename = "timeLastUsed", skip_serializing_if = "Option::is_none")]
+    pub time_last_used: Option<u64>,
+    #[serde(rename = "timeLastModified", skip_serializing_if = "Option::is_none")]
+    pub time_last_modified: Option<u64>,
+    #[serde(rename = "timesUsed", skip_serializing_if = "Option::is_none")]
+    pub times_used: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    #[serde(flatten)]
+    unknown_fields: UnknownFields,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreditCard {
+    id: String,
+    pub entry: CreditCardEntry,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct CreditCardEntry {
+    /// Name of the card holder
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cc_name: Option<String>,
+    /// `None` if Firefox only sent the number encrypted with its device key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cc_number: Option<SecUtf8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cc_exp_month: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cc_exp_year: Option<u32>,
+    /// Network of the card, such as `visa` or `mastercard`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cc_type: Option<String>,
+    #[serde(rename = "timeCreated", skip_serializing_if = "Option::is_none")]
+    pub time_created: Option<u64>,
+    #[serde(rename = "timeLastUsed", skip_serializing_if = "Option::is_none")]
+    pub time_last_used: Option<u64>,
+    #[serde(rename = "timeLastModified", skip_serializing_if = "Option::is_none")]
+    pub time_last_modified: Option<u64>,
+    #[serde(rename = "timesUsed", skip_serializing_if = "Option::is_none")]
+    pub times_used: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    #[serde(flatten)]
+    unknown_fields: UnknownFields,
+}
+
+impl AddressEntry {
+    /// Set the modification time to now, after changing fields.
+    pub fn touch(&mut self) {
+        self.time_last_modified = Some(unix_time_millis());
+    }
+}
+
+impl CreditCardEntry {
+    /// Set the modification time to now, after changing fields.
+    pub fn touch(&mut self) {
+        self.time_last_modified = Some(unix_time_millis());
+    }
+
+    pub fn cc_number(&self) -> Option<&str> {
+        self.cc_number.as_ref().map(SecUtf8::unsecure)
+    }
+
+    /// Set the card number, dropping the copy Firefox encrypted with the key
+    /// of its device so that it doesn't keep the old one.
+    pub fn set_cc_number(&mut self, cc_number: &str) {
+        self.cc_number = Some(cc_number.into());
+        self.unknown_fields.remove("cc-number-enc");
+    }
+}
+
+impl Address {
+    /// A new address record, with the current time as creation time.
+    pub fn new(mut entry: AddressEntry) -> Self {
+        let now = unix_time_millis();
+        entry.time_created.get_or_insert(now);
+        entry.time_last_modified.get_or_insert(now);
+        entry.version.get_or_insert(ADDRESS_VERSION);
+        Self {
+            id: generate_bso_id(),
+            entry,
+        }
+    }
+}
+
+impl CreditCard {
+    /// A new credit card record, with the current time as creation time.
+    pub fn new(mut entry: CreditCardEntry) -> Self {
+        let now = unix_time_millis();
+        entry.time_created.get_or_insert(now);
+        entry.time_last_modified.get_or_insert(now);
+        entry.version.get_or_insert(CREDIT_CARD_VERSION);
+        Self {
+            id: generate_bso_id(),
+            entry,
+        }
+    }
+}
+
+impl BsoObject for Address {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl BsoObject for CreditCard {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl SyncClient {
+    pub fn addresses(&self) -> Collection<'_, Address> {
+        self.collection("addresses")
+    }
+
+    pub fn credit_cards(&self) -> Collection<'_, CreditCard> {
+        self.collection("creditcards")
+    }
+
+    /// Download and decrypt the whole addresses collection.
+    pub async fn get_addresses(&self) -> Result<Vec<Address>> {
+        self.addresses().fetch().await
+    }
+
+    pub async fn put_addresses(&self, addresses: &[Address]) -> Result<UploadReport> {
+        self.addresses().upload(addresses).await
+    }
+
+    /// Download and decrypt the whole credit cards collection.
+    pub async fn get_credit_cards(&self) -> Result<Vec<CreditCard>> {
+        self.credit_cards().fetch().await
+    }
+
+    pub async fn put_credit_cards(&self, credit_cards: &[CreditCard]) -> Result<UploadReport> {
+        self.credit_cards().upload(credit_cards).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credit_card_test() {
+        let mut credit_card: CreditCard = serde_json::from_str(
+            r#"{"id":"kD2dbJn1Dc8I","entry":{"cc-name":"Jane Doe","cc-number-enc":"MDEyMzQ1Njc4OQ==","cc-exp-month":4,"cc-exp-year":2027,"cc-type":"visa","timeCreated":1626895557678,"timesUsed":2,"version":3}}"#,
+        )
+        .unwrap();
+        assert_eq!(credit_card.entry.cc_name.as_deref(), Some("Jane Doe"));
+        assert_eq!(credit_card.entry.cc_number(), None);
+        assert_eq!(credit_card.entry.cc_exp_year, Some(2027));
+
+        credit_card.entry.set_cc_number("4111111111111111");
+        let serialized = serde_json::to_value(&credit_card).unwrap();
+        assert_eq!(serialized["entry"]["cc-number"], "4111111111111111");
+        assert_eq!(serialized["entry"]["timesUsed"], 2);
+        assert!(serialized["entry"].get("cc-number-enc").is_none());
+    }
+
+    #[test]
+    fn address_test() {
+        let address = Address::new(AddressEntry {
+            given_name: Some("Jane".to_string()),
+            postal_code: Some("75001".to_string()),
+            ..AddressEntry::default()
+        });
+        let serialized = serde_json::to_value(&address).unwrap();
+        assert_eq!(serialized["entry"]["given-name"], "Jane");
+        assert_eq!(serialized["entry"]["postal-code"], "75001");
+        assert_eq!(serialized["entry"]["version"], ADDRESS_VERSION);
+        assert!(serialized["entry"].get("family-name").is_none());
+
+        let deserialized: Address = serde_json::from_value(serialized).unwrap();
+        assert_eq!(deserialized.id(), address.id());
+        assert_eq!(deserialized.entry.given_name.as_deref(), Some("Jane"));
+    }
+}
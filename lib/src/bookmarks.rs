@@ -0,0 +1,547 @@
+//! Records of the `bookmarks` collection, which form a tree of folders rooted
+//! at the menu, toolbar, other bookmarks and mobile folders.
+
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+};
+use url::Url;
+
+use crate::{
+    generate_bso_id, unix_time_millis, BsoObject, Collection, Error, Record, Result, SyncClient,
+    UploadReport,
+};
+
+/// Parent of the root folders
+pub const PLACES_ROOT: &str = "places";
+/// IDs of the folders at the root of the tree, which always exist
+pub const ROOT_FOLDERS: &[&str] = &["menu", "toolbar", "unfiled", "mobile"];
+
+type UnknownFields = serde_json::Map<String, serde_json::Value>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Bookmark {
+    id: String,
+    #[serde(rename = "parentid")]
+    pub parent_id: String,
+    pub parent_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<u64>,
+    pub title: Option<String>,
+    #[serde(rename = "bmkUri")]
+    pub uri: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyword: Option<String>,
+    #[serde(flatten)]
+    unknown_fields: UnknownFields,
+}
+
+/// A saved search of the history or bookmarks, with a `place:` URI.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Query {
+    id: String,
+    #[serde(rename = "parentid")]
+    pub parent_id: String,
+    pub parent_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<u64>,
+    pub title: Option<String>,
+    #[serde(rename = "bmkUri")]
+    pub uri: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyword: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub folder_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query_id: Option<String>,
+    #[serde(flatten)]
+    unknown_fields: UnknownFields,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Folder {
+    id: String,
+    #[serde(rename = "parentid")]
+    pub parent_id: String,
+    pub parent_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<u64>,
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// IDs of the items of the folder, in order
+    #[serde(default)]
+    pub children: Vec<String>,
+    #[serde(flatten)]
+    unknown_fields: UnknownFields,
+}
+
+/// A folder filled from a feed, which Firefox no longer supports.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Livemark {
+    id: String,
+    #[serde(rename = "parentid")]
+    pub parent_id: String,
+    pub parent_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<u64>,
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub feed_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub site_uri: Option<String>,
+    #[serde(flatten)]
+    unknown_fields: UnknownFields,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Separator {
+    id: String,
+    #[serde(rename = "parentid")]
+    pub parent_id: String,
+    pub parent_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<u64>,
+    #[serde(flatten)]
+    unknown_fields: UnknownFields,
+}
+
+/// A record of the `bookmarks` collection.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BookmarkItem {
+    Bookmark(Bookmark),
+    Query(Query),
+    Folder(Folder),
+    Livemark(Livemark),
+    Separator(Separator),
+}
+
+impl Bookmark {
+    /// A new bookmark in the other bookmarks folder, until it's added to
+    /// another one with [`Folder::push`].
+    pub fn new(title: impl Into<String>, uri: &Url) -> Self {
+        Self {
+            id: generate_bso_id(),
+            parent_id: "unfiled".to_string(),
+            parent_name: None,
+            date_added: Some(unix_time_millis()),
+            title: Some(title.into()),
+            uri: uri.to_string(),
+            description: None,
+            tags: Vec::new(),
+            keyword: None,
+            unknown_fields: UnknownFields::new(),
+        }
+    }
+}
+
+impl Folder {
+    /// A new empty folder in the other bookmarks folder, until it's added to
+    /// another one with [`Folder::push`].
+    pub fn new(title: impl Into<String>) -> Self {
+        Self {
+            id: generate_bso_id(),
+            parent_id: "unfiled".to_string(),
+            parent_name: None,
+            date_added: Some(unix_time_millis()),
+            title: Some(title.into()),
+            description: None,
+            children: Vec::new(),
+            unknown_fields: UnknownFields::new(),
+        }
+    }
+
+    /// Move `item` to the end of this folder.
+    ///
+    /// The folder it was in before still lists it, and has to be updated too.
+    pub fn push(&mut self, item: &mut BookmarkItem) {
+        let (parent_id, parent_name) = item.parent_mut();
+        *parent_id = self.id.clone();
+        *parent_name = self.title.clone();
+        self.children.push(item.id().to_string());
+    }
+}
+
+impl Separator {
+    /// A new separator in the other bookmarks folder, until it's added to
+    /// another one with [`Folder::push`].
+    pub fn new() -> Self {
+        Self {
+            id: generate_bso_id(),
+            parent_id: "unfiled".to_string(),
+            parent_name: None,
+            date_added: Some(unix_time_millis()),
+            unknown_fields: UnknownFields::new(),
+        }
+    }
+}
+
+impl Default for Separator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BookmarkItem {
+    pub fn parent_id(&self) -> &str {
+        match self {
+            BookmarkItem::Bookmark(item) => &item.parent_id,
+            BookmarkItem::Query(item) => &item.parent_id,
+            BookmarkItem::Folder(item) => &item.parent_id,
+            BookmarkItem::Livemark(item) => &item.parent_id,
+            BookmarkItem::Separator(item) => &item.parent_id,
+        }
+    }
+
+    fn parent_mut(&mut self) -> (&mut String, &mut Option<String>) {
+        match self {
+            BookmarkItem::Bookmark(item) => (&mut item.parent_id, &mut item.parent_name),
+            BookmarkItem::Query(item) => (&mut item.parent_id, &mut item.parent_name),
+            BookmarkItem::Folder(item) => (&mut item.parent_id, &mut item.parent_name),
+            BookmarkItem::Livemark(item) => (&mut item.parent_id, &mut item.parent_name),
+            BookmarkItem::Separator(item) => (&mut item.parent_id, &mut item.parent_name),
+        }
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        match self {
+            BookmarkItem::Bookmark(item) => item.title.as_deref(),
+            BookmarkItem::Query(item) => item.title.as_deref(),
+            BookmarkItem::Folder(item) => item.title.as_deref(),
+            BookmarkItem::Livemark(item) => item.title.as_deref(),
+            BookmarkItem::Separator(_) => None,
+        }
+    }
+
+    /// The IDs of the items of a folder, or `None` if this isn't one.
+    pub fn children(&self) -> Option<&[String]> {
+        match self {
+            BookmarkItem::Folder(folder) => Some(&folder.children),
+            _ => None,
+        }
+    }
+}
+
+impl BsoObject for BookmarkItem {
+    fn id(&self) -> &str {
+        match self {
+            BookmarkItem::Bookmark(item) => &item.id,
+            BookmarkItem::Query(item) => &item.id,
+            BookmarkItem::Folder(item) => &item.id,
+            BookmarkItem::Livemark(item) => &item.id,
+            BookmarkItem::Separator(item) => &item.id,
+        }
+    }
+}
+
+impl From<Bookmark> for BookmarkItem {
+    fn from(bookmark: Bookmark) -> Self {
+        BookmarkItem::Bookmark(bookmark)
+    }
+}
+
+impl From<Folder> for BookmarkItem {
+    fn from(folder: Folder) -> Self {
+        BookmarkItem::Folder(folder)
+    }
+}
+
+impl From<Separator> for BookmarkItem {
+    fn from(separator: Separator) -> Self {
+        BookmarkItem::Separator(separator)
+    }
+}
+
+/// An inconsistency between the `parentid` of the items and the `children`
+/// of the folders.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TreeProblem {
+    /// The parent of the item isn't a folder of the tree.
+    Orphan { id: String, parent_id: String },
+    /// The folder lists a child that isn't in the tree.
+    MissingChild { folder: String, child: String },
+    /// The parent of the item doesn't list it among its children.
+    NotInParent { id: String, parent_id: String },
+    /// The item is listed among the children of another folder than its
+    /// parent.
+    Misplaced {
+        id: String,
+        parent_id: String,
+        folder: String,
+    },
+}
+
+impl fmt::Display for TreeProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeProblem::Orphan { id, parent_id } => {
+                write!(f, "{} has no folder {} to be in", id, parent_id)
+            }
+            TreeProblem::MissingChild { folder, child } => {
+                write!(f, "{} contains {} which doesn't exist", folder, child)
+            }
+            TreeProblem::NotInParent { id, parent_id } => {
+                write!(f, "{} isn't among the children of {}", id, parent_id)
+            }
+            TreeProblem::Misplaced {
+                id,
+                parent_id,
+                folder,
+            } => write!(f, "{} is in {} instead of {}", id, folder, parent_id),
+        }
+    }
+}
+
+/// Add `root_folders` to `items`, listing among their children the items of
+/// `items` that they contain but don't list yet.
+fn adopt_into_root_folders(items: &mut Vec<BookmarkItem>, root_folders: Vec<Folder>) {
+    for mut root_folder in root_folders {
+        for item in items.iter() {
+            if item.parent_id() == root_folder.id
+                && !root_folder.children.iter().any(|child| child == item.id())
+            {
+                root_folder.children.push(item.id().to_string());
+            }
+        }
+        items.push(BookmarkItem::Folder(root_folder));
+    }
+}
+
+/// Check that every item of `items` is in a folder that lists it, and that
+/// every child of its folders is among `items`.
+///
+/// The root folders themselves don't need a parent among `items`, but they
+/// have to be part of it for the items they contain to be listed. Their
+/// children aren't required to be among `items`, as they usually hold the
+/// bookmarks of other clients too.
+pub fn validate_tree(items: &[BookmarkItem]) -> Result<()> {
+    let by_id: HashMap<&str, &BookmarkItem> = items.iter().map(|item| (item.id(), item)).collect();
+    let mut problems = Vec::new();
+    for item in items {
+        if ROOT_FOLDERS.contains(&item.id()) {
+            continue;
+        }
+        let parent_id = item.parent_id();
+        match by_id.get(parent_id).and_then(|parent| parent.children()) {
+            Some(children) => {
+                if !children.iter().any(|child| child == item.id()) {
+                    problems.push(TreeProblem::NotInParent {
+                        id: item.id().to_string(),
+                        parent_id: parent_id.to_string(),
+                    });
+                }
+            }
+            None => problems.push(TreeProblem::Orphan {
+                id: item.id().to_string(),
+                parent_id: parent_id.to_string(),
+            }),
+        }
+    }
+    for folder in items {
+        if ROOT_FOLDERS.contains(&folder.id()) {
+            continue;
+        }
+        for child in folder.children().unwrap_or_default() {
+            match by_id.get(child.as_str()) {
+                None => problems.push(TreeProblem::MissingChild {
+                    folder: folder.id().to_string(),
+                    child: child.clone(),
+                }),
+                Some(item) if item.parent_id() != folder.id() => {
+                    problems.push(TreeProblem::Misplaced {
+                        id: child.clone(),
+                        parent_id: item.parent_id().to_string(),
+                        folder: folder.id().to_string(),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::InvalidBookmarkTree(problems))
+    }
+}
+
+impl SyncClient {
+    pub fn bookmarks(&self) -> Collection<'_, BookmarkItem> {
+        self.collection("bookmarks")
+    }
+
+    /// Download and decrypt the whole bookmarks collection.
+    pub async fn get_bookmarks(&self) -> Result<Vec<BookmarkItem>> {
+        self.bookmarks().fetch().await
+    }
+
+    /// Upload `items` after checking with [`validate_tree`] that none of them
+    /// would end up outside of the tree.
+    ///
+    /// The root folders that contain some of `items` are downloaded when they
+    /// aren't part of `items`, so that the new items are added to their
+    /// children.
+    pub async fn put_bookmarks(&self, items: &[BookmarkItem]) -> Result<UploadReport> {
+        let ids: BTreeSet<_> = items.iter().map(BookmarkItem::id).collect();
+        let mut root_folders = Vec::new();
+        for root_id in ROOT_FOLDERS {
+            if ids.contains(root_id) || !items.iter().any(|item| item.parent_id() == *root_id) {
+                continue;
+            }
+            match self.bookmarks().get(root_id).await {
+                Ok(Record::Object(BookmarkItem::Folder(root_folder))) => {
+                    root_folders.push(root_folder)
+                }
+                // Left for validate_tree to report the items as orphans
+                Ok(_) | Err(Error::SyncServer(StatusCode::NOT_FOUND)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        let mut items = items.to_vec();
+        adopt_into_root_folders(&mut items, root_folders);
+        validate_tree(&items)?;
+        self.bookmarks().upload(&items).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deserialize_bookmarks_test() {
+        let items: Vec<BookmarkItem> = serde_json::from_str(
+            r#"[
+  {"id":"toolbar","type":"folder","parentid":"places","parentName":"","title":"Bookmarks Toolbar","children":["kD2dbJn1Dc8I","5fAxGX8Wc9zq"]},
+  {"id":"kD2dbJn1Dc8I","type":"bookmark","parentid":"toolbar","parentName":"Bookmarks Toolbar","title":"Rust","bmkUri":"https://www.rust-lang.org/","tags":[],"keyword":null,"loadInSidebar":false},
+  {"id":"5fAxGX8Wc9zq","type":"separator","parentid":"toolbar","parentName":"Bookmarks Toolbar","pos":1},
+  {"id":"CZtAJxrSHbA0","type":"query","parentid":"unfiled","title":"Recent Tags","bmkUri":"place:type=6&sort=14&maxResults=10","folderName":"Recent Tags"},
+  {"id":"bQb4oYWmjLxP","type":"livemark","parentid":"unfiled","title":"News","feedUri":"https://example.com/feed","siteUri":"https://example.com/","children":[]},
+  {"id":"unfiled","type":"folder","parentid":"places","parentName":"","title":"Other Bookmarks","children":["CZtAJxrSHbA0","bQb4oYWmjLxP"]}
+]"#,
+        )
+        .unwrap();
+        assert!(matches!(&items[0], BookmarkItem::Folder(folder) if folder.children.len() == 2));
+        assert!(
+            matches!(&items[1], BookmarkItem::Bookmark(bookmark) if bookmark.uri == "https://www.rust-lang.org/")
+        );
+        assert!(matches!(&items[2], BookmarkItem::Separator(_)));
+        assert!(
+            matches!(&items[3], BookmarkItem::Query(query) if query.folder_name.as_deref() == Some("Recent Tags"))
+        );
+        assert!(
+            matches!(&items[4], BookmarkItem::Livemark(livemark) if livemark.feed_uri.is_some())
+        );
+
+        let serialized = serde_json::to_value(&items[2]).unwrap();
+        assert_eq!(serialized["type"], "separator");
+        assert_eq!(serialized["pos"], 1);
+        assert_eq!(
+            serde_json::to_value(&items[1]).unwrap()["loadInSidebar"],
+            false
+        );
+        validate_tree(&items).unwrap();
+    }
+
+    #[test]
+    fn validate_tree_test() {
+        let mut folder = Folder::new("Project");
+        let mut bookmark = BookmarkItem::from(Bookmark::new(
+            "Docs",
+            &Url::parse("https://docs.rs").unwrap(),
+        ));
+        let mut separator = BookmarkItem::from(Separator::new());
+        folder.push(&mut bookmark);
+        folder.push(&mut separator);
+        assert_eq!(bookmark.parent_id(), folder.id);
+        assert!(
+            matches!(&bookmark, BookmarkItem::Bookmark(bookmark) if bookmark.parent_name.as_deref() == Some("Project"))
+        );
+
+        let mut items = vec![
+            BookmarkItem::from(folder.clone()),
+            bookmark.clone(),
+            separator.clone(),
+        ];
+        // The new folder isn't listed by the unfiled folder of the server yet
+        let problems = match validate_tree(&items) {
+            Err(Error::InvalidBookmarkTree(problems)) => problems,
+            result => panic!("unexpected result {:?}", result),
+        };
+        assert_eq!(
+            problems,
+            [TreeProblem::Orphan {
+                id: folder.id.clone(),
+                parent_id: "unfiled".to_string(),
+            }]
+        );
+        let root_folder: Folder = serde_json::from_str(
+            r#"{"id":"unfiled","parentid":"places","parentName":"","title":"Other Bookmarks","children":["kD2dbJn1Dc8I"]}"#,
+        )
+        .unwrap();
+        adopt_into_root_folders(&mut items, vec![root_folder]);
+        assert!(
+            matches!(&items[3], BookmarkItem::Folder(root_folder) if root_folder.children == ["kD2dbJn1Dc8I", folder.id.as_str()])
+        );
+        validate_tree(&items).unwrap();
+
+        let unfiled = BookmarkItem::from(Bookmark::new(
+            "Lost",
+            &Url::parse("https://example.com").unwrap(),
+        ));
+        let mut orphan_folder = folder.clone();
+        orphan_folder.parent_id = "R0Pw5hn6m1Xr".to_string();
+        folder.children.push("VtZxJRWZYL2m".to_string());
+        let items = vec![
+            BookmarkItem::from(orphan_folder),
+            bookmark,
+            separator,
+            unfiled.clone(),
+        ];
+        let problems = match validate_tree(&items) {
+            Err(Error::InvalidBookmarkTree(problems)) => problems,
+            result => panic!("unexpected result {:?}", result),
+        };
+        assert_eq!(
+            problems,
+            [
+                TreeProblem::Orphan {
+                    id: folder.id.clone(),
+                    parent_id: "R0Pw5hn6m1Xr".to_string(),
+                },
+                TreeProblem::Orphan {
+                    id: unfiled.id().to_string(),
+                    parent_id: "unfiled".to_string(),
+                }
+            ]
+        );
+
+        let items = vec![BookmarkItem::from(folder.clone()), unfiled.clone()];
+        let problems = match validate_tree(&items) {
+            Err(Error::InvalidBookmarkTree(problems)) => problems,
+            result => panic!("unexpected result {:?}", result),
+        };
+        assert_eq!(problems.len(), 5);
+        assert!(problems.contains(&TreeProblem::MissingChild {
+            folder: folder.id.clone(),
+            child: "VtZxJRWZYL2m".to_string(),
+        }));
+    }
+}
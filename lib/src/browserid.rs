@@ -0,0 +1,132 @@
+//! Deprecated BrowserID authentication to the token server, only used when
+//! the OAuth flow fails.
+
+use log::info;
+use rand::rngs::OsRng;
+use rsa::{hash::Hash, padding::PaddingScheme, PublicKeyParts, RSAPrivateKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    fxa_response, hawk_authenticate, session_token_credentials, Error, FxaClient, Result,
+    SyncServerCredentials,
+};
+
+const DURATION: u64 = 60;
+
+#[derive(Serialize)]
+struct PublicKey<'a> {
+    algorithm: &'a str,
+    n: &'a str,
+    e: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CertificateSignRequest<'a> {
+    public_key: PublicKey<'a>,
+    duration: u64,
+}
+
+#[derive(Deserialize)]
+struct CertificateSignResponse {
+    cert: String,
+}
+
+#[derive(Serialize)]
+struct Assertion<'a> {
+    exp: u64,
+    aud: &'a str,
+}
+
+impl FxaClient {
+    pub(crate) async fn browserid_sync_server_credentials(
+        &self,
+        session_token: &str,
+        client_state: &str,
+    ) -> Result<SyncServerCredentials> {
+        let browserid_assertion = self.get_browserid_assertion(session_token).await?;
+        Ok(self
+            .sync_server_tokens(
+                &format!("BrowserID {}", browserid_assertion),
+                "X-Client-State",
+                client_state,
+            )
+            .await?
+            .into())
+    }
+
+    async fn certificate_sign(
+        &self,
+        n: &str,
+        e: &str,
+        credentials: &hawk::Credentials,
+    ) -> Result<(CertificateSignResponse, u64)> {
+        let url = format!("{}/certificate/sign", self.base_uri);
+        let mut request = self
+            .client
+            .post(url)
+            .json(&CertificateSignRequest {
+                public_key: PublicKey {
+                    algorithm: "RS",
+                    n,
+                    e,
+                },
+                // Value in milliseconds
+                duration: DURATION * 1000,
+            })
+            .build()?;
+        hawk_authenticate(&mut request, credentials)?;
+        let response = self.client.execute(request).await?;
+        let server_time = response
+            .headers()
+            .get("timestamp")
+            .and_then(|timestamp| timestamp.to_str().ok())
+            .and_then(|timestamp| timestamp.parse().ok())
+            .ok_or_else(|| Error::MalformedRecord("missing server timestamp".to_string()))?;
+
+        Ok((fxa_response(response).await?, server_time))
+    }
+
+    async fn get_browserid_assertion(&self, session_token: &str) -> Result<String> {
+        info!("Generating RSA private key, this may take a while");
+        let rsa_private_key = RSAPrivateKey::new(&mut OsRng, 2048).map_err(|_| Error::Crypto)?;
+        let (certificate, server_time) = self
+            .certificate_sign(
+                &rsa_private_key.n().to_str_radix(10),
+                &rsa_private_key.e().to_str_radix(10),
+                &session_token_credentials(session_token)?,
+            )
+            .await?;
+
+        let signed_data = format!(
+            "{}.{}",
+            base64::encode_config("{\"alg\": \"RS256\"}", base64::URL_SAFE_NO_PAD),
+            base64::encode_config(
+                &serde_json::to_string(&Assertion {
+                    exp: (server_time + DURATION) * 1000,
+                    aud: &self.audience,
+                })?,
+                base64::URL_SAFE_NO_PAD
+            ),
+        );
+
+        let assertion = format!(
+            "{}.{}",
+            &signed_data,
+            base64::encode_config(
+                rsa_private_key
+                    .sign(
+                        PaddingScheme::PKCS1v15Sign {
+                            hash: Some(Hash::SHA2_256),
+                        },
+                        &Sha256::new().chain(&signed_data).finalize(),
+                    )
+                    .map_err(|_| Error::Crypto)?,
+                base64::URL_SAFE_NO_PAD,
+            )
+        );
+
+        Ok(format!("{}~{}", certificate.cert, assertion))
+    }
+}
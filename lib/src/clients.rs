@@ -0,0 +1,160 @@
+//! Records of the `clients` collection, in which every device syncing with the
+//! account describes itself. Firefox lists them as the connected devices.
+
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    generate_bso_id, BsoObject, Collection, Error, Record, Result, SyncClient, UploadReport,
+};
+
+/// Seconds after which the record of a client that stopped syncing expires,
+/// as Firefox does
+const CLIENT_TTL: u64 = 21 * 24 * 60 * 60;
+/// Version of the sync protocol this client speaks
+const PROTOCOL_VERSION: &str = "1.5";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Client {
+    id: String,
+    /// Name shown among the connected devices
+    pub name: String,
+    /// `desktop`, `mobile`, `tablet` or `tv`
+    #[serde(rename = "type")]
+    pub client_type: String,
+    /// Version of the application
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default)]
+    protocols: Vec<String>,
+    /// Commands other clients sent to this one, such as wiping an engine
+    #[serde(default)]
+    commands: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fxa_device_id: Option<String>,
+    #[serde(flatten)]
+    unknown_fields: serde_json::Map<String, serde_json::Value>,
+    /// Set from the BSO, not part of the payload
+    #[serde(skip)]
+    modified: Option<f64>,
+}
+
+impl Client {
+    /// A new client record with a random ID, which should be kept to update
+    /// the same record later.
+    pub fn new(name: impl Into<String>, client_type: impl Into<String>) -> Self {
+        Self::with_id(generate_bso_id(), name, client_type)
+    }
+
+    pub fn with_id(
+        id: impl Into<String>,
+        name: impl Into<String>,
+        client_type: impl Into<String>,
+    ) -> Self {
+        Self {
+            id: id.into(),
+            name: name.into(),
+            client_type: client_type.into(),
+            version: None,
+            protocols: vec![PROTOCOL_VERSION.to_string()],
+            commands: Vec::new(),
+            os: None,
+            fxa_device_id: None,
+            unknown_fields: serde_json::Map::new(),
+            modified: None,
+        }
+    }
+
+    pub fn fxa_device_id(&self) -> Option<&str> {
+        self.fxa_device_id.as_deref()
+    }
+
+    /// Time the client last uploaded its record, in seconds since the epoch.
+    pub fn modified(&self) -> Option<f64> {
+        self.modified
+    }
+}
+
+impl BsoObject for Client {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn ttl(&self) -> Option<u64> {
+        Some(CLIENT_TTL)
+    }
+}
+
+impl SyncClient {
+    pub fn clients(&self) -> Collection<'_, Client> {
+        self.collection("clients")
+    }
+
+    /// Download the records of all clients, including this one if it's
+    /// registered.
+    pub async fn get_clients(&self) -> Result<Vec<Client>> {
+        Ok(self
+            .clients()
+            .fetch_since(None)
+            .await?
+            .records
+            .into_iter()
+            .filter_map(|(modified, record)| match record {
+                Record::Object(client) => Some(Client {
+                    modified: Some(modified),
+                    ..client
+                }),
+                Record::Tombstone(_) => None,
+            })
+            .collect())
+    }
+
+    /// Create or update the record of this client, renewing its expiry.
+    ///
+    /// The fields that other clients set on an existing record are kept,
+    /// except for the commands they sent, which are dropped as none of them
+    /// apply here.
+    pub async fn register_client(&self, client: &Client) -> Result<UploadReport> {
+        let record = match self.clients().get(client.id()).await {
+            Ok(Record::Object(existing)) => Client {
+                fxa_device_id: client.fxa_device_id.clone().or(existing.fxa_device_id),
+                unknown_fields: existing.unknown_fields,
+                ..client.clone()
+            },
+            Ok(Record::Tombstone(_)) | Err(Error::SyncServer(StatusCode::NOT_FOUND)) => {
+                client.clone()
+            }
+            Err(err) => return Err(err),
+        };
+        self.clients().upload(&[record]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_test() {
+        let client: Client = serde_json::from_str(
+            r#"{"id":"kD2dbJn1Dc8I","name":"Firefox on laptop","type":"desktop","commands":[{"command":"wipeEngine","args":["history"]}],"version":"90.0","protocols":["1.5"],"os":"Linux","appPackage":"org.mozilla.firefox","application":"Firefox","fxaDeviceId":"a2a7d1c36b1ab1c2"}"#,
+        )
+        .unwrap();
+        assert_eq!(client.name, "Firefox on laptop");
+        assert_eq!(client.client_type, "desktop");
+        assert_eq!(client.fxa_device_id(), Some("a2a7d1c36b1ab1c2"));
+        assert_eq!(client.unknown_fields["application"], "Firefox");
+
+        let mut own = Client::with_id("5fAxGX8Wc9zq", "pass-fxa", "desktop");
+        own.version = Some("0.3.0".to_string());
+        assert_eq!(own.ttl(), Some(CLIENT_TTL));
+        let serialized = serde_json::to_value(&own).unwrap();
+        assert_eq!(serialized["type"], "desktop");
+        assert_eq!(serialized["protocols"], serde_json::json!(["1.5"]));
+        assert_eq!(serialized["commands"], serde_json::json!([]));
+        assert!(serialized.get("fxaDeviceId").is_none());
+    }
+}
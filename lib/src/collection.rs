@@ -0,0 +1,214 @@
+use log::debug;
+use serde::{de, Deserialize, Serialize};
+use std::marker::PhantomData;
+
+use crate::{decrypt_records, BsoObject, Result, SyncClient, UploadReport};
+
+/// What's left of a record once it's deleted, so that other clients delete it
+/// too.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Tombstone {
+    id: String,
+    deleted: bool,
+}
+
+impl Tombstone {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            deleted: true,
+        }
+    }
+}
+
+impl BsoObject for Tombstone {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// A record of a collection, or its tombstone.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Record<T> {
+    // Tried first, as records with only optional fields would match any object
+    Tombstone(Tombstone),
+    Object(T),
+}
+
+impl<T: BsoObject> BsoObject for Record<T> {
+    fn id(&self) -> &str {
+        match self {
+            Record::Tombstone(tombstone) => tombstone.id(),
+            Record::Object(object) => object.id(),
+        }
+    }
+
+    fn ttl(&self) -> Option<u64> {
+        match self {
+            Record::Tombstone(tombstone) => tombstone.ttl(),
+            Record::Object(object) => object.ttl(),
+        }
+    }
+}
+
+/// Records downloaded by [`Collection::fetch_since`].
+#[derive(Debug)]
+pub struct Changes<T> {
+    /// Records modified since, along with the time they were modified
+    pub records: Vec<(f64, Record<T>)>,
+    /// Last-modified time of the collection, to fetch the next changes from
+    pub last_modified: Option<f64>,
+    /// Whether the collection was wiped since, in which case `records` is the
+    /// whole collection
+    pub reset: bool,
+}
+
+/// The records of a collection of the sync storage, such as `passwords`,
+/// as objects of type `T`.
+pub struct Collection<'a, T> {
+    client: &'a SyncClient,
+    name: &'a str,
+    object: PhantomData<T>,
+}
+
+impl<'a, T> Collection<'a, T>
+where
+    T: BsoObject + de::DeserializeOwned,
+{
+    pub(crate) fn new(client: &'a SyncClient, name: &'a str) -> Self {
+        Self {
+            client,
+            name,
+            object: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name
+    }
+
+    /// Download and decrypt a single record.
+    pub async fn get(&self, id: &str) -> Result<Record<T>> {
+        self.client
+            .get_storage_object(format!("{}/{}", self.name, id))
+            .await
+    }
+
+    /// Download and decrypt the whole collection, leaving out tombstones.
+    pub async fn fetch(&self) -> Result<Vec<T>> {
+        Ok(self
+            .fetch_since(None)
+            .await?
+            .records
+            .into_iter()
+            .filter_map(|(_, record)| match record {
+                Record::Object(object) => Some(object),
+                Record::Tombstone(_) => None,
+            })
+            .collect())
+    }
+
+    /// Download and decrypt the records modified after `newer`, including
+    /// tombstones, or the whole collection if it's `None`.
+    pub async fn fetch_since(&self, mut newer: Option<f64>) -> Result<Changes<T>> {
+        let mut changes = Changes {
+            records: Vec::new(),
+            last_modified: None,
+            reset: false,
+        };
+        let mut offset = None;
+        loop {
+            let page = self
+                .client
+                .get_bsos(self.name, newer, offset.as_deref())
+                .await?;
+            if offset.is_none() {
+                if let (Some(since), Some(current)) = (newer, page.last_modified) {
+                    if current < since {
+                        // The collection was wiped since, start over
+                        newer = None;
+                        changes.reset = true;
+                        continue;
+                    }
+                }
+                changes.last_modified = page.last_modified;
+            }
+            for record in decrypt_records(page.bsos, self.client.key_bundle(self.name)) {
+                changes.records.push(record?);
+            }
+            debug!(
+                "Downloaded {} records of {}",
+                changes.records.len(),
+                self.name
+            );
+            match page.next_offset {
+                Some(next_offset) => offset = Some(next_offset),
+                None => break,
+            }
+        }
+        changes.last_modified = changes.last_modified.or(newer);
+        if let Some(last_modified) = changes.last_modified {
+            self.client.set_last_modified(self.name, last_modified);
+        }
+        Ok(changes)
+    }
+
+    /// Replace the records with tombstones.
+    pub async fn delete(&self, ids: &[&str]) -> Result<UploadReport> {
+        let tombstones: Vec<_> = ids.iter().map(|id| Tombstone::new(*id)).collect();
+        self.client
+            .upload_with_retries(self.name, &tombstones)
+            .await
+    }
+
+    /// Remove the records from the server entirely, without leaving
+    /// tombstones for other clients.
+    pub async fn purge(&self, ids: &[&str]) -> Result<()> {
+        self.client.delete_ids(self.name, ids).await
+    }
+}
+
+impl<T> Collection<'_, T>
+where
+    T: BsoObject + Serialize,
+{
+    /// Encrypt and upload `objects`, replacing the records with the same IDs.
+    pub async fn upload(&self, objects: &[T]) -> Result<UploadReport> {
+        self.client.upload_with_retries(self.name, objects).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Note {
+        id: String,
+        #[serde(default)]
+        text: Option<String>,
+    }
+
+    impl BsoObject for Note {
+        fn id(&self) -> &str {
+            &self.id
+        }
+    }
+
+    #[test]
+    fn record_test() {
+        let tombstone: Record<Note> =
+            serde_json::from_str(r#"{"id":"CZtAJxrSHbA0","deleted":true}"#).unwrap();
+        assert!(matches!(&tombstone, Record::Tombstone(_)));
+        assert_eq!(tombstone.id(), "CZtAJxrSHbA0");
+
+        let note: Record<Note> =
+            serde_json::from_str(r#"{"id":"5fAxGX8Wc9zq","text":"hello"}"#).unwrap();
+        assert!(matches!(&note, Record::Object(Note { text: Some(text), .. }) if text == "hello"));
+
+        let serialized =
+            serde_json::to_value(Record::<Note>::Tombstone(Tombstone::new("a"))).unwrap();
+        assert_eq!(serialized, serde_json::json!({"id": "a", "deleted": true}));
+    }
+}
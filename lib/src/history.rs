@@ -0,0 +1,69 @@
+//! Records of the `history` collection, one per visited page, only read by
+//! this library.
+
+use serde::Deserialize;
+
+use crate::{BsoObject, Result, SyncClient};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HistoryRecord {
+    id: String,
+    #[serde(rename = "histUri")]
+    pub uri: String,
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Most recent visits of the page
+    #[serde(default)]
+    pub visits: Vec<Visit>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Visit {
+    /// Time of the visit, in microseconds since the epoch
+    pub date: u64,
+    /// How the page was reached, such as by following a link or typing its URL
+    #[serde(rename = "type")]
+    pub transition: u32,
+}
+
+impl HistoryRecord {
+    /// Time of the last visit of the page, in microseconds since the epoch.
+    pub fn last_visit(&self) -> Option<u64> {
+        self.visits.iter().map(|visit| visit.date).max()
+    }
+}
+
+impl BsoObject for HistoryRecord {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl SyncClient {
+    /// Download and decrypt the history synced by all clients.
+    pub async fn get_history(&self) -> Result<Vec<HistoryRecord>> {
+        self.collection("history").fetch().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_record_test() {
+        let record: HistoryRecord = serde_json::from_str(
+            r#"{"id":"kD2dbJn1Dc8I","histUri":"https://www.rust-lang.org/learn","title":"Learn Rust","visits":[{"date":1626895557678000,"type":1},{"date":1626899157678000,"type":2}]}"#,
+        )
+        .unwrap();
+        assert_eq!(record.uri, "https://www.rust-lang.org/learn");
+        assert_eq!(record.last_visit(), Some(1626899157678000));
+
+        let untitled: HistoryRecord = serde_json::from_str(
+            r#"{"id":"5fAxGX8Wc9zq","histUri":"https://example.com/","title":null,"visits":[]}"#,
+        )
+        .unwrap();
+        assert_eq!(untitled.title, None);
+        assert_eq!(untitled.last_visit(), None);
+    }
+}
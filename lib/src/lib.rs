@@ -1,31 +1,336 @@
 use aes::Aes256;
 use block_modes::{block_padding::Pkcs7, BlockMode, Cbc};
-use futures::{stream::FuturesUnordered, StreamExt};
 use hkdf::Hkdf;
 use hmac::{Hmac, Mac, NewMac};
+use log::{debug, info, warn};
 use rand::{rngs::OsRng, RngCore};
-use reqwest::{header, Request, StatusCode};
-use rsa::{hash::Hash, padding::PaddingScheme, PublicKeyParts, RSAPrivateKey};
+use reqwest::{header, Request, RequestBuilder, StatusCode};
 use secstr::SecUtf8;
 use serde::{de, Deserialize, Serialize, Serializer};
 use sha2::{Digest, Sha256};
 use std::{
-    collections::HashMap,
-    io::{self, Write},
+    collections::{BTreeMap, HashMap},
+    fmt, io,
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
 };
 use tokio::time::{sleep, Duration};
 use url::Url;
 
-const DURATION: u64 = 60;
-const BATCH_SIZE: usize = 100;
+pub mod autofill;
+pub mod bookmarks;
+#[cfg(feature = "browserid")]
+mod browserid;
+pub mod clients;
+mod collection;
+pub mod history;
+pub mod tabs;
+mod totp;
+
+pub use collection::{Changes, Collection, Record, Tombstone};
+
+use totp::OtpAuth;
+
+/// Number of IDs the sync server accepts in a single DELETE request
+const MAX_DELETE_IDS: usize = 100;
+/// Number of times records rejected by the server are uploaded again
+const UPLOAD_RETRIES: u32 = 3;
+/// Number of times a request is sent while the server is unavailable
+const MAX_REQUEST_ATTEMPTS: u32 = 3;
+/// Longest time in seconds to wait for the server to be available again
+const MAX_RETRY_WAIT: u64 = 60;
+/// Number of records requested per page when downloading a collection
+const FETCH_LIMIT: usize = 1000;
+/// Seconds before their expiry at which token server credentials are renewed
+const TOKEN_EXPIRY_MARGIN: u64 = 60;
+
+const DEFAULT_AUTH_URL: &str = "https://api.accounts.firefox.com/v1";
+const DEFAULT_TOKEN_SERVER_URL: &str = "https://token.services.mozilla.com/1.0/sync/1.5";
+const STAGE_AUTH_URL: &str = "https://api-accounts.stage.mozaws.net/v1";
+const STAGE_TOKEN_SERVER_URL: &str = "https://token.stage.mozaws.net/1.0/sync/1.5";
+
+const OLDSYNC_SCOPE: &str = "https://identity.mozilla.com/apps/oldsync";
+/// OAuth client ID of Firefox desktop, which is allowed to request the sync scope
+const OAUTH_CLIENT_ID: &str = "5882386c6d801776";
+/// Lifetime in seconds requested for OAuth access tokens
+const OAUTH_TOKEN_TTL: u64 = 3600;
+
+/// Errors that can occur while talking to FxA or the sync server.
+#[derive(Debug)]
+pub enum Error {
+    /// The HTTP request could not be sent or its response could not be read.
+    Transport(reqwest::Error),
+    /// The FxA auth server rejected the request.
+    Fxa { errno: u16, message: String },
+    /// The token server answered with an unexpected HTTP status.
+    TokenServer(StatusCode),
+    /// The sync server answered with an unexpected HTTP status.
+    SyncServer(StatusCode),
+    /// The collection was modified on the sync server since it was fetched,
+    /// so the write was rejected.
+    Conflict,
+    /// The server is overloaded or in maintenance, and asked to come back
+    /// after `retry_after` seconds if it said when.
+    Unavailable { retry_after: Option<u64> },
+    /// The token server moved the account to another sync server while the
+    /// client was using it.
+    NodeReassigned,
+    /// The sync storage or engine has a newer version than this client
+    /// supports, so writing to it could corrupt it.
+    UnsupportedStorage(String),
+    /// A key had the wrong size, decryption failed or a HMAC didn't verify.
+    Crypto,
+    /// A record or response could not be decoded.
+    MalformedRecord(String),
+    /// Bookmarks to upload would end up outside of the bookmark tree.
+    InvalidBookmarkTree(Vec<bookmarks::TreeProblem>),
+    /// FxA asked for a verification method that isn't supported.
+    UnsupportedVerification(String),
+    /// The two-step authentication code was rejected.
+    InvalidTotpCode,
+    /// FxA asked for a two-step authentication code, but no way to get one
+    /// was set on the [`SyncClientBuilder`].
+    TotpRequired,
+    /// FxA blocked the sign-in and sent an unblock code by email, but no way
+    /// to get it was set on the [`SyncClientBuilder`].
+    UnblockCodeRequired,
+    /// Reading user input failed.
+    Io(io::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transport(err) => write!(f, "HTTP request failed: {}", err),
+            Error::Fxa { errno, message } => write!(f, "FxA error {}: {}", errno, message),
+            Error::TokenServer(status) => write!(f, "token server responded with {}", status),
+            Error::SyncServer(status) => write!(f, "sync server responded with {}", status),
+            Error::Conflict => write!(f, "the collection was modified on the sync server"),
+            Error::Unavailable {
+                retry_after: Some(retry_after),
+            } => write!(
+                f,
+                "server unavailable, try again in {} seconds",
+                retry_after
+            ),
+            Error::Unavailable { retry_after: None } => {
+                write!(f, "server unavailable, try again later")
+            }
+            Error::NodeReassigned => write!(f, "the account was moved to another sync server"),
+            Error::UnsupportedStorage(version) => write!(f, "unsupported {}", version),
+            Error::Crypto => write!(f, "cryptographic verification failed"),
+            Error::MalformedRecord(reason) => write!(f, "malformed record: {}", reason),
+            Error::InvalidBookmarkTree(problems) => {
+                write!(f, "invalid bookmark tree: ")?;
+                for (i, problem) in problems.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", problem)?;
+                }
+                Ok(())
+            }
+            Error::UnsupportedVerification(method) => {
+                write!(f, "unsupported verification method `{}`", method)
+            }
+            Error::InvalidTotpCode => write!(f, "invalid two-step authentication code"),
+            Error::TotpRequired => write!(f, "two-step authentication code required"),
+            Error::UnblockCodeRequired => {
+                write!(f, "sign-in blocked, an unblock code was sent by email")
+            }
+            Error::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport(err) => Some(err),
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_decode() {
+            Error::MalformedRecord(err.to_string())
+        } else {
+            Error::Transport(err)
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::MalformedRecord(err.to_string())
+    }
+}
+
+impl From<base64::DecodeError> for Error {
+    fn from(err: base64::DecodeError) -> Self {
+        Error::MalformedRecord(err.to_string())
+    }
+}
+
+impl From<hex::FromHexError> for Error {
+    fn from(err: hex::FromHexError) -> Self {
+        Error::MalformedRecord(err.to_string())
+    }
+}
+
+impl From<hmac::crypto_mac::MacError> for Error {
+    fn from(_: hmac::crypto_mac::MacError) -> Self {
+        Error::Crypto
+    }
+}
+
+impl From<hmac::crypto_mac::InvalidKeyLength> for Error {
+    fn from(_: hmac::crypto_mac::InvalidKeyLength) -> Self {
+        Error::Crypto
+    }
+}
+
+impl From<block_modes::BlockModeError> for Error {
+    fn from(_: block_modes::BlockModeError) -> Self {
+        Error::Crypto
+    }
+}
+
+impl From<block_modes::InvalidKeyIvLength> for Error {
+    fn from(_: block_modes::InvalidKeyIvLength) -> Self {
+        Error::Crypto
+    }
+}
+
+impl From<hkdf::InvalidLength> for Error {
+    fn from(_: hkdf::InvalidLength) -> Self {
+        Error::Crypto
+    }
+}
+
+impl From<hawk::Error> for Error {
+    fn from(_: hawk::Error) -> Self {
+        Error::Crypto
+    }
+}
 
 #[derive(Deserialize)]
 struct CryptoKeyRecord {
     default: Vec<String>,
+    /// Keys of the collections that don't use the default one
+    #[serde(default)]
+    collections: HashMap<String, Vec<String>>,
+}
+
+/// Decode an encryption key and HMAC key pair from `crypto/keys`.
+fn decode_key_bundle(keys: &[String]) -> Result<[u8; 64]> {
+    let (encryption_key, hmac_key) = match keys {
+        [encryption_key, hmac_key] => (encryption_key, hmac_key),
+        _ => {
+            return Err(Error::MalformedRecord(
+                "crypto/keys must contain pairs of keys".to_string(),
+            ))
+        }
+    };
+    let mut key_bundle = [0u8; 64];
+    if base64::decode_config_slice(encryption_key, base64::STANDARD, &mut key_bundle)? != 32
+        || base64::decode_config_slice(hmac_key, base64::STANDARD, &mut key_bundle[32..64])? != 32
+    {
+        return Err(Error::Crypto);
+    }
+    Ok(key_bundle)
+}
+
+/// Version of the storage format this client understands
+const STORAGE_VERSION: u64 = 5;
+
+/// Versions of the engines this client can write records for
+const ENGINE_VERSIONS: &[(&str, u64)] = &[
+    ("passwords", 1),
+    ("bookmarks", 2),
+    ("addresses", 1),
+    ("creditcards", 1),
+    ("clients", 1),
+];
+
+#[derive(Deserialize)]
+struct MetaGlobalBso {
+    #[serde(with = "serde_with::json::nested")]
+    payload: MetaGlobal,
+}
+
+/// Versions and sync IDs of the storage and its engines, from `meta/global`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MetaGlobal {
+    storage_version: u64,
+    #[serde(default)]
+    engines: HashMap<String, EngineMeta>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EngineMeta {
+    version: u64,
+    /// Changes when the engine data is reset, which invalidates local copies
+    #[serde(rename = "syncID")]
+    sync_id: String,
+}
+
+impl MetaGlobal {
+    /// Fail unless records of `collection` can be written to this storage.
+    fn check_writable(&self, collection: &str) -> Result<()> {
+        if self.storage_version != STORAGE_VERSION {
+            return Err(Error::UnsupportedStorage(format!(
+                "storage version {}",
+                self.storage_version
+            )));
+        }
+        let supported_version = ENGINE_VERSIONS
+            .iter()
+            .find(|(engine, _)| *engine == collection)
+            .map(|(_, version)| *version);
+        match (self.engines.get(collection), supported_version) {
+            (Some(engine), Some(supported_version)) if engine.version > supported_version => {
+                Err(Error::UnsupportedStorage(format!(
+                    "{} engine version {}",
+                    collection, engine.version
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 pub trait BsoObject {
     fn id(&self) -> &str;
+
+    /// Seconds after which the server deletes the record if it isn't
+    /// uploaded again, or `None` to keep it forever.
+    fn ttl(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl<T: BsoObject> BsoObject for &T {
+    fn id(&self) -> &str {
+        (*self).id()
+    }
+
+    fn ttl(&self) -> Option<u64> {
+        (*self).ttl()
+    }
 }
 
 fn origin_serialize<S: Serializer>(hostname: &Url, s: S) -> Result<S::Ok, S::Error> {
@@ -39,7 +344,7 @@ pub struct Login {
     #[serde(serialize_with = "origin_serialize")]
     pub hostname: Url,
     #[serde(rename = "formSubmitURL")]
-    form_submit_url: String,
+    form_submit_url: Option<String>,
     http_realm: Option<String>,
     pub username: String,
     pub password: SecUtf8,
@@ -47,14 +352,19 @@ pub struct Login {
     password_field: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     time_last_used: Option<u64>,
-    // TODO: update this
     #[serde(skip_serializing_if = "Option::is_none")]
     time_created: Option<u64>,
-    // TODO: update this
     #[serde(skip_serializing_if = "Option::is_none")]
     time_password_changed: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    time_used: Option<u64>,
+    times_used: Option<u64>,
+    /// Fields of the record this version doesn't know about, kept as is so
+    /// that they aren't lost when the login is uploaded again
+    #[serde(flatten)]
+    unknown_fields: serde_json::Map<String, serde_json::Value>,
+    /// Set from the BSO, not part of the payload
+    #[serde(skip)]
+    modified: Option<f64>,
 }
 
 impl BsoObject for Login {
@@ -63,25 +373,6 @@ impl BsoObject for Login {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Deleted {
-    id: String,
-    deleted: bool,
-}
-
-impl BsoObject for Deleted {
-    fn id(&self) -> &str {
-        &self.id
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(untagged)]
-enum PasswordBSORecord {
-    Password(Login),
-    Deleted(Deleted),
-}
-
 fn generate_bso_id() -> String {
     let bytes: [u8; 9] = rand::random();
     base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
@@ -92,7 +383,7 @@ impl Login {
         Self {
             id: generate_bso_id(),
             hostname,
-            form_submit_url: String::new(),
+            form_submit_url: Some(String::new()),
             http_realm: None,
             username: username.to_string(),
             password: password.into(),
@@ -101,16 +392,198 @@ impl Login {
             time_created: None,
             time_last_used: None,
             time_password_changed: None,
-            time_used: None,
+            times_used: None,
+            unknown_fields: serde_json::Map::new(),
+            modified: None,
         }
     }
 
+    /// A copy of the login with another password. The time the password was
+    /// changed is set to now if it differs.
     pub fn with_password(&self, new_password: &str) -> Self {
+        if self.password.unsecure() == new_password {
+            return self.clone();
+        }
         Self {
             password: new_password.into(),
+            time_password_changed: Some(unix_time_millis()),
             ..self.clone()
         }
     }
+
+    /// Start building a login, see [`LoginBuilder`].
+    pub fn builder(username: &str, password: &str, hostname: Url) -> LoginBuilder {
+        LoginBuilder::new(username, password, hostname)
+    }
+
+    /// Origin the login form submits to, `None` for HTTP authentication.
+    pub fn form_submit_url(&self) -> Option<&str> {
+        self.form_submit_url.as_deref()
+    }
+
+    /// Realm of the HTTP authentication, `None` for form logins.
+    pub fn http_realm(&self) -> Option<&str> {
+        self.http_realm.as_deref()
+    }
+
+    /// Name of the username field of the login form.
+    pub fn username_field(&self) -> &str {
+        &self.username_field
+    }
+
+    /// Name of the password field of the login form.
+    pub fn password_field(&self) -> &str {
+        &self.password_field
+    }
+
+    /// When the login was created, in milliseconds since the Unix epoch.
+    pub fn time_created(&self) -> Option<u64> {
+        self.time_created
+    }
+
+    /// When the login was last used, in milliseconds since the Unix epoch.
+    pub fn time_last_used(&self) -> Option<u64> {
+        self.time_last_used
+    }
+
+    /// When the password was last changed, in milliseconds since the Unix epoch.
+    pub fn time_password_changed(&self) -> Option<u64> {
+        self.time_password_changed
+    }
+
+    /// How many times Firefox filled in the login.
+    pub fn times_used(&self) -> Option<u64> {
+        self.times_used
+    }
+
+    /// When the sync server last stored the record, in seconds since the Unix
+    /// epoch. `None` for logins that weren't downloaded.
+    pub fn modified(&self) -> Option<f64> {
+        self.modified
+    }
+}
+
+/// Builds a [`Login`], or a changed copy of an existing one with
+/// `LoginBuilder::from(login)`.
+#[derive(Debug, Clone)]
+pub struct LoginBuilder {
+    login: Login,
+}
+
+impl LoginBuilder {
+    /// A form login with a new ID, created now.
+    pub fn new(username: &str, password: &str, hostname: Url) -> Self {
+        Self {
+            login: Login::new(username, password, hostname),
+        }
+    }
+
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.login.id = id.into();
+        self
+    }
+
+    pub fn hostname(mut self, hostname: Url) -> Self {
+        self.login.hostname = hostname;
+        self
+    }
+
+    pub fn username(mut self, username: impl Into<String>) -> Self {
+        self.login.username = username.into();
+        self
+    }
+
+    /// Set the password, and the time it was changed to now if it differs.
+    pub fn password(mut self, password: &str) -> Self {
+        self.login = self.login.with_password(password);
+        self
+    }
+
+    /// Make it a form login submitting to `form_submit_url`, which may be
+    /// empty if unknown.
+    pub fn form_submit_url(mut self, form_submit_url: impl Into<String>) -> Self {
+        self.login.form_submit_url = Some(form_submit_url.into());
+        self.login.http_realm = None;
+        self
+    }
+
+    /// Make it an HTTP authentication login for `http_realm`.
+    pub fn http_realm(mut self, http_realm: impl Into<String>) -> Self {
+        self.login.http_realm = Some(http_realm.into());
+        self.login.form_submit_url = None;
+        self
+    }
+
+    pub fn username_field(mut self, username_field: impl Into<String>) -> Self {
+        self.login.username_field = username_field.into();
+        self
+    }
+
+    pub fn password_field(mut self, password_field: impl Into<String>) -> Self {
+        self.login.password_field = password_field.into();
+        self
+    }
+
+    pub fn time_created(mut self, time_created: u64) -> Self {
+        self.login.time_created = Some(time_created);
+        self
+    }
+
+    pub fn time_last_used(mut self, time_last_used: u64) -> Self {
+        self.login.time_last_used = Some(time_last_used);
+        self
+    }
+
+    pub fn time_password_changed(mut self, time_password_changed: u64) -> Self {
+        self.login.time_password_changed = Some(time_password_changed);
+        self
+    }
+
+    pub fn times_used(mut self, times_used: u64) -> Self {
+        self.login.times_used = Some(times_used);
+        self
+    }
+
+    pub fn build(self) -> Login {
+        self.login
+    }
+}
+
+impl From<Login> for LoginBuilder {
+    fn from(login: Login) -> Self {
+        Self { login }
+    }
+}
+
+/// Remote logins kept between runs, so that only the records modified since
+/// can be downloaded with [`SyncClient::update_logins`].
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct LoginCache {
+    /// Storage node the logins were downloaded from
+    api_endpoint: String,
+    /// Sync ID of the passwords engine when the logins were downloaded
+    sync_id: Option<String>,
+    /// Last-modified time of the passwords collection, as sent by the server
+    last_modified: Option<f64>,
+    logins: BTreeMap<String, CachedLogin>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CachedLogin {
+    modified: f64,
+    login: Login,
+}
+
+impl LoginCache {
+    pub fn logins(&self) -> Vec<Login> {
+        self.logins
+            .values()
+            .map(|cached| Login {
+                modified: Some(cached.modified),
+                ..cached.login.clone()
+            })
+            .collect()
+    }
 }
 
 #[derive(Serialize)]
@@ -130,12 +603,74 @@ struct AccountLoginRequest<'a, 'b, 'c> {
 struct SyncServerToken {
     id: String,
     key: String,
-    uid: u64,
     api_endpoint: String,
-    duration: u32,
-    hashalg: String,
-    hashed_fxa_uid: String,
-    node_type: String,
+    duration: u64,
+}
+
+/// Credentials for the sync server, as handed out by the token server.
+#[derive(Serialize, Deserialize, Clone)]
+struct SyncServerCredentials {
+    id: String,
+    key: SecUtf8,
+    api_endpoint: String,
+    /// Unix timestamp, in seconds, at which the credentials expire
+    expires_at: u64,
+}
+
+impl From<SyncServerToken> for SyncServerCredentials {
+    fn from(token: SyncServerToken) -> Self {
+        Self {
+            id: token.id,
+            key: token.key.into(),
+            api_endpoint: token.api_endpoint,
+            expires_at: unix_time() + token.duration,
+        }
+    }
+}
+
+impl SyncServerCredentials {
+    fn is_expired(&self) -> bool {
+        unix_time() + TOKEN_EXPIRY_MARGIN >= self.expires_at
+    }
+
+    fn hawk_credentials(&self) -> Result<hawk::Credentials> {
+        Ok(hawk::Credentials {
+            id: self.id.clone(),
+            key: hawk::Key::new(
+                self.key.unsecure().as_bytes(),
+                hawk::DigestAlgorithm::Sha256,
+            )?,
+        })
+    }
+}
+
+/// An authenticated FxA session.
+///
+/// It can be serialized, kept somewhere safe and restored with
+/// [`SyncClientBuilder::restore`] so that later runs don't have to log in again.
+/// It contains the keys to decrypt all synced data, so it must be stored
+/// encrypted.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Session {
+    email: String,
+    session_token: SecUtf8,
+    client_state: String,
+    /// Sync key bundle derived from kB, hex encoded
+    sync_key_bundle: SecUtf8,
+    sync_server: SyncServerCredentials,
+}
+
+impl Session {
+    /// Email address of the account this session belongs to.
+    pub fn email(&self) -> &str {
+        &self.email
+    }
+
+    fn sync_key_bundle(&self) -> Result<[u8; 64]> {
+        let mut sync_key_bundle = [0u8; 64];
+        hex::decode_to_slice(self.sync_key_bundle.unsecure(), &mut sync_key_bundle)?;
+        Ok(sync_key_bundle)
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -147,52 +682,59 @@ struct Payload {
 }
 
 type Aes256Cbc = Cbc<Aes256, Pkcs7>;
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Deserialize, Serialize)]
 struct BSO {
     id: String,
-    //modified: f64,
+    #[serde(default, skip_serializing)]
+    modified: f64,
     #[serde(with = "serde_with::json::nested")]
     payload: Payload,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl: Option<u64>,
 }
 
 impl BSO {
-    fn from_object(object: &(impl BsoObject + Serialize), key: &[u8], hmac_key: &[u8]) -> Self {
+    fn from_object(
+        object: &(impl BsoObject + Serialize),
+        key: &[u8],
+        hmac_key: &[u8],
+    ) -> Result<Self> {
         let iv = generate_iv();
-        let cipher = Aes256Cbc::new_from_slices(key, &iv).unwrap();
-        let mut payload = serde_json::to_vec(&object).unwrap();
+        let cipher = Aes256Cbc::new_from_slices(key, &iv)?;
+        let mut payload = serde_json::to_vec(&object)?;
         let plaintext_len = payload.len();
         payload.extend_from_slice(&[0u8; 16][0..16 - plaintext_len % 16]);
-        cipher.encrypt(&mut payload, plaintext_len).unwrap();
+        cipher.encrypt(&mut payload, plaintext_len)?;
         let ciphertext_base64 = base64::encode(payload);
-        let mut mac = Hmac::<Sha256>::new_from_slice(hmac_key).unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(hmac_key)?;
         mac.update(ciphertext_base64.as_bytes());
-        //cipher.encrypt(buffer, pos)
-        BSO {
+        Ok(BSO {
             id: object.id().to_string(),
+            modified: 0.0,
+            ttl: object.ttl(),
             payload: Payload {
                 iv: base64::encode(iv),
                 ciphertext: ciphertext_base64,
                 hmac: hex::encode(mac.finalize().into_bytes()),
             },
-        }
+        })
     }
 
-    fn decrypt_payload(&self, key: &[u8], hmac_key: &[u8]) -> Vec<u8> {
+    fn decrypt_payload(&self, key: &[u8], hmac_key: &[u8]) -> Result<Vec<u8>> {
         let payload = &self.payload;
 
-        let cipher =
-            Aes256Cbc::new_from_slices(key, &base64::decode(&payload.iv).unwrap()).unwrap();
-        let mut ciphertext = base64::decode(&payload.ciphertext).unwrap();
-        let len = cipher.decrypt(&mut ciphertext).unwrap().len();
-
-        let mut mac_verifier = Hmac::<Sha256>::new_from_slice(hmac_key).unwrap();
+        // Verify the HMAC before touching the ciphertext
+        let mut mac_verifier = Hmac::<Sha256>::new_from_slice(hmac_key)?;
         mac_verifier.update(payload.ciphertext.as_bytes());
-        mac_verifier
-            .verify(&hex::decode(&payload.hmac).unwrap())
-            .unwrap();
+        mac_verifier.verify(&hex::decode(&payload.hmac)?)?;
+
+        let cipher = Aes256Cbc::new_from_slices(key, &base64::decode(&payload.iv)?)?;
+        let mut ciphertext = base64::decode(&payload.ciphertext)?;
+        let len = cipher.decrypt(&mut ciphertext)?.len();
 
         ciphertext.truncate(len);
-        ciphertext
+        Ok(ciphertext)
     }
 }
 
@@ -221,21 +763,34 @@ impl<'a, 'b, 'c> AccountLoginRequest<'a, 'b, 'c> {
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 struct AccountLoginResponse {
-    uid: String,
     session_token: String,
     key_fetch_token: String,
     verification_method: Option<String>,
-    verified: bool,
 }
 
 #[derive(Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
 struct BadRequestError {
-    code: u16,
     errno: u16,
     message: String,
-    verification_method: Option<String>,
-    verification_reason: Option<String>,
+    #[serde(default, rename = "retryAfter")]
+    retry_after: Option<u64>,
+}
+
+/// errno of FxA responses to clients sending too many requests
+const ERRNO_TOO_MANY_REQUESTS: u16 = 114;
+
+impl From<BadRequestError> for Error {
+    fn from(err: BadRequestError) -> Self {
+        match err.errno {
+            ERRNO_TOO_MANY_REQUESTS => Error::Unavailable {
+                retry_after: err.retry_after,
+            },
+            errno => Error::Fxa {
+                errno,
+                message: err.message,
+            },
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -244,22 +799,39 @@ struct SendUnblockCodeRequest<'a> {
 }
 
 #[derive(Serialize)]
-struct PublicKey<'a> {
-    algorithm: &'a str,
-    n: &'a str,
-    e: &'a str,
+struct VerifyTotpRequest<'a> {
+    code: &'a str,
+}
+
+#[derive(Deserialize)]
+struct VerifyTotpResponse {
+    success: bool,
 }
 
 #[derive(Serialize)]
+struct ScopedKeyDataRequest<'a> {
+    client_id: &'a str,
+    scope: &'a str,
+}
+
+#[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct CertificateSignRequest<'a> {
-    public_key: PublicKey<'a>,
-    duration: u64,
+struct ScopedKeyData {
+    key_rotation_timestamp: u64,
+}
+
+#[derive(Serialize)]
+struct OAuthTokenRequest<'a> {
+    client_id: &'a str,
+    grant_type: &'a str,
+    scope: &'a str,
+    access_type: &'a str,
+    ttl: u64,
 }
 
 #[derive(Deserialize)]
-struct CertificateSignResponse {
-    cert: String,
+struct OAuthTokenResponse {
+    access_token: String,
 }
 
 #[derive(Deserialize)]
@@ -269,63 +841,380 @@ struct AccountKeysResponse {
 
 pub struct SyncClient {
     http_client: reqwest::Client,
+    /// To get new sync server credentials when they expire
+    fxa_client: FxaClient,
     api_endpoint: String,
-    sync_server_credentials: hawk::Credentials,
+    /// Default key bundle of the collections
     key_bundle: [u8; 64],
+    /// Key bundles of the collections that have their own
+    collection_key_bundles: HashMap<String, [u8; 64]>,
+    /// `None` if the storage isn't initialized
+    meta_global: Option<MetaGlobal>,
+    session: Mutex<Session>,
+    /// Last-modified time of the collections as last seen, to only write to
+    /// them if they weren't modified since
+    last_modified: Mutex<HashMap<String, f64>>,
+    configuration: ServerConfiguration,
+    /// Last `X-Weave-Alert` sent by the server
+    alert: Mutex<Option<String>>,
+    /// Longest `X-Weave-Backoff` sent by the server
+    backoff: Mutex<Option<u64>>,
+}
+
+/// Limits of the sync server, from `info/configuration`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+struct ServerConfiguration {
+    /// Records in a single POST
+    max_post_records: usize,
+    /// Bytes of records in a single POST
+    max_post_bytes: usize,
+    /// Records in a batch
+    max_total_records: usize,
+    /// Bytes of records in a batch
+    max_total_bytes: usize,
+}
+
+impl Default for ServerConfiguration {
+    /// The limits of servers that don't have `info/configuration`.
+    fn default() -> Self {
+        Self {
+            max_post_records: 100,
+            max_post_bytes: 256 * 1024,
+            max_total_records: 10_000,
+            max_total_bytes: 100 * 1024 * 1024,
+        }
+    }
+}
+
+impl ServerConfiguration {
+    /// Split records of `sizes` bytes into batches of POSTs, as ranges of
+    /// records, so that every POST and batch stays within the limits.
+    fn split(&self, sizes: &[usize]) -> Vec<Vec<Range<usize>>> {
+        let mut batches = Vec::new();
+        let mut posts = Vec::new();
+        let (mut batch_records, mut batch_bytes) = (0, 0);
+        let (mut post_start, mut post_bytes) = (0, 0);
+        for (i, size) in sizes.iter().enumerate() {
+            // Records are sent as a JSON array, separated by commas
+            let size = size + 1;
+            let post_records = i - post_start;
+            if post_records > 0
+                && (post_records == self.max_post_records
+                    || post_bytes + size > self.max_post_bytes)
+            {
+                posts.push(post_start..i);
+                post_start = i;
+                post_bytes = 0;
+            }
+            if batch_records > 0
+                && (batch_records == self.max_total_records
+                    || batch_bytes + size > self.max_total_bytes)
+            {
+                if post_start < i {
+                    posts.push(post_start..i);
+                    post_start = i;
+                    post_bytes = 0;
+                }
+                batches.push(std::mem::take(&mut posts));
+                batch_records = 0;
+                batch_bytes = 0;
+            }
+            post_bytes += size;
+            batch_records += 1;
+            batch_bytes += size;
+        }
+        if post_start < sizes.len() {
+            posts.push(post_start..sizes.len());
+        }
+        if !posts.is_empty() {
+            batches.push(posts);
+        }
+        batches
+    }
 }
 
 struct FxaClient {
     client: reqwest::Client,
     base_uri: String,
+    token_server_url: String,
+    #[cfg(feature = "browserid")]
+    audience: String,
+    totp: TotpSource,
+    unblock_code: Option<Callback<UnblockCodeFn>>,
+    email_confirmation: Option<Callback<EmailConfirmationFn>>,
+}
+
+/// Where the code for two-step authentication comes from.
+#[derive(Clone)]
+enum TotpSource {
+    Missing,
+    Callback(Arc<dyn Fn() -> String + Send + Sync>),
+    Secret(OtpAuth),
+}
+
+impl fmt::Debug for TotpSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't leak the TOTP secret
+        f.write_str(match self {
+            TotpSource::Missing => "Missing",
+            TotpSource::Callback(_) => "Callback",
+            TotpSource::Secret(_) => "Secret",
+        })
+    }
+}
+
+type UnblockCodeFn = dyn Fn(&str) -> String + Send + Sync;
+type EmailConfirmationFn = dyn Fn(&str) + Send + Sync;
+
+/// A callback set on the [`SyncClientBuilder`].
+struct Callback<F: ?Sized>(Arc<F>);
+
+impl<F: ?Sized> Clone for Callback<F> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Callback<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Callback")
+    }
+}
+
+/// Configuration of the servers a [`SyncClient`] talks to.
+///
+/// By default Mozilla's production servers are used.
+#[derive(Clone, Debug)]
+pub struct SyncClientBuilder {
+    auth_url: Url,
+    token_server_url: Url,
+    #[cfg(feature = "browserid")]
+    audience: Option<String>,
+    totp: TotpSource,
+    unblock_code: Option<Callback<UnblockCodeFn>>,
+    email_confirmation: Option<Callback<EmailConfirmationFn>>,
 }
 
 enum Verification<'a> {
     EmailCaptcha(&'a str),
 }
 
-fn hawk_authenticate(request: &mut Request, credentials: &hawk::Credentials) {
+fn hawk_authenticate(request: &mut Request, credentials: &hawk::Credentials) -> Result<()> {
     let method = request.method().clone();
     let url = request.url().clone();
-    let mut hawk_request_builder = hawk::RequestBuilder::from_url(method.as_str(), &url).unwrap();
+    let mut hawk_request_builder = hawk::RequestBuilder::from_url(method.as_str(), &url)?;
     let payload_hash;
     if let Some(body) = request.body() {
+        let content_type = request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|content_type| content_type.as_bytes())
+            .unwrap_or_default();
         payload_hash = hawk::PayloadHasher::hash(
-            request.headers().get("Content-Type").unwrap().as_bytes(),
+            content_type,
             hawk::SHA256,
-            body.as_bytes().unwrap(),
-        )
-        .unwrap();
+            body.as_bytes().unwrap_or_default(),
+        )?;
         hawk_request_builder = hawk_request_builder.hash(&payload_hash[..]);
     }
 
     let hawk_request = hawk_request_builder.request();
-    assert!(request
-        .headers_mut()
-        .insert(
-            header::AUTHORIZATION,
-            format!("Hawk {}", hawk_request.make_header(credentials).unwrap())
-                .parse()
-                .unwrap(),
-        )
-        .is_none());
+    request.headers_mut().insert(
+        header::AUTHORIZATION,
+        format!("Hawk {}", hawk_request.make_header(credentials)?)
+            .parse()
+            .map_err(|_| Error::Crypto)?,
+    );
+    Ok(())
 }
 
-impl FxaClient {
-    fn new() -> Self {
-        Self {
-            client: reqwest::Client::builder()
-                .user_agent("reqwest (pass-fxa)")
-                //.proxy(reqwest::Proxy::all("http://localhost:8080").unwrap())
-                //.danger_accept_invalid_certs(true)
-                .build()
-                .unwrap(),
-            // TODO: allow user to choose FxA server
-            base_uri: "https://api.accounts.firefox.com/v1".to_string(),
+/// Turn an FxA auth server response into `T`, or into the error it describes.
+async fn fxa_response<T: de::DeserializeOwned>(response: reqwest::Response) -> Result<T> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response.json().await?);
+    }
+    let retry_after = retry_after(&response);
+    match response.json::<BadRequestError>().await {
+        Ok(err) => Err(err.into()),
+        // Rate limiting may happen in front of FxA, without an FxA error
+        Err(_) if is_unavailable(status) => Err(Error::Unavailable { retry_after }),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn is_unavailable(status: StatusCode) -> bool {
+    status == StatusCode::SERVICE_UNAVAILABLE || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Value in seconds of the header `name`, if any.
+fn header_seconds(response: &reqwest::Response, name: &str) -> Option<u64> {
+    response
+        .headers()
+        .get(name)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Seconds to wait before sending requests again, as asked in `Retry-After`
+/// or `X-Weave-Backoff`.
+fn retry_after(response: &reqwest::Response) -> Option<u64> {
+    header_seconds(response, "Retry-After").or_else(|| header_seconds(response, "X-Weave-Backoff"))
+}
+
+/// Turn a sync server response into `T`, failing on any non-success status.
+async fn sync_response<T: de::DeserializeOwned>(response: reqwest::Response) -> Result<T> {
+    match response.status() {
+        status if status.is_success() => Ok(response.json().await?),
+        StatusCode::PRECONDITION_FAILED => Err(Error::Conflict),
+        status => Err(Error::SyncServer(status)),
+    }
+}
+
+impl Default for SyncClientBuilder {
+    fn default() -> Self {
+        Self {
+            auth_url: Url::parse(DEFAULT_AUTH_URL).unwrap(),
+            token_server_url: Url::parse(DEFAULT_TOKEN_SERVER_URL).unwrap(),
+            #[cfg(feature = "browserid")]
+            audience: None,
+            totp: TotpSource::Missing,
+            unblock_code: None,
+            email_confirmation: None,
+        }
+    }
+}
+
+impl SyncClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use Mozilla's stage environment instead of production.
+    pub fn stage() -> Self {
+        Self {
+            auth_url: Url::parse(STAGE_AUTH_URL).unwrap(),
+            token_server_url: Url::parse(STAGE_TOKEN_SERVER_URL).unwrap(),
+            #[cfg(feature = "browserid")]
+            audience: None,
+            totp: TotpSource::Missing,
+            unblock_code: None,
+            email_confirmation: None,
+        }
+    }
+
+    /// Set the FxA auth server URL, including the API version, e.g.
+    /// `https://api.accounts.firefox.com/v1`.
+    pub fn auth_url(mut self, auth_url: Url) -> Self {
+        self.auth_url = auth_url;
+        self
+    }
+
+    /// Set the token server URL, e.g.
+    /// `https://token.services.mozilla.com/1.0/sync/1.5`.
+    pub fn token_server_url(mut self, token_server_url: Url) -> Self {
+        self.token_server_url = token_server_url;
+        self
+    }
+
+    /// Set the audience of the BrowserID assertion given to the token server.
+    ///
+    /// Defaults to the origin of the token server URL.
+    #[cfg(feature = "browserid")]
+    pub fn audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// Get the two-step authentication code from `callback` when FxA asks for
+    /// one.
+    ///
+    /// Without it or [`SyncClientBuilder::totp_uri`], logging in to an account
+    /// with two-step authentication fails with [`Error::TotpRequired`].
+    pub fn totp_callback(mut self, callback: impl Fn() -> String + Send + Sync + 'static) -> Self {
+        self.totp = TotpSource::Callback(Arc::new(callback));
+        self
+    }
+
+    /// Generate the two-step authentication code from an `otpauth://totp/`
+    /// URI when FxA asks for one.
+    pub fn totp_uri(mut self, uri: &str) -> Result<Self> {
+        self.totp = TotpSource::Secret(OtpAuth::parse(uri)?);
+        Ok(self)
+    }
+
+    /// Get the unblock code FxA sends by email when it blocks a sign-in from
+    /// `callback`, which is given the email address.
+    ///
+    /// Without it, a blocked sign-in fails with [`Error::UnblockCodeRequired`].
+    pub fn unblock_code_callback(
+        mut self,
+        callback: impl Fn(&str) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.unblock_code = Some(Callback(Arc::new(callback)));
+        self
+    }
+
+    /// Call `callback` with the email address when FxA asks to confirm the
+    /// sign-in by email, before waiting for the confirmation.
+    pub fn email_confirmation_callback(
+        mut self,
+        callback: impl Fn(&str) + Send + Sync + 'static,
+    ) -> Self {
+        self.email_confirmation = Some(Callback(Arc::new(callback)));
+        self
+    }
+
+    /// Log in to FxA and connect to the sync server of the account.
+    pub async fn login(self, email: &str, password: &str) -> Result<SyncClient> {
+        FxaClient::new(self)?.get_sync_client(email, password).await
+    }
+
+    /// Connect to the sync server using a previously saved [`Session`].
+    ///
+    /// FxA is only contacted if the token server credentials of the session
+    /// have expired.
+    pub async fn restore(self, mut session: Session) -> Result<SyncClient> {
+        let fxa_client = FxaClient::new(self)?;
+        if session.sync_server.is_expired() {
+            debug!("Sync server credentials expired, requesting new ones");
+            session.sync_server = fxa_client
+                .sync_server_credentials(session.session_token.unsecure(), &session.client_state)
+                .await?;
         }
+        SyncClient::from_session(fxa_client, session).await
+    }
+}
+
+impl FxaClient {
+    fn new(config: SyncClientBuilder) -> Result<Self> {
+        let token_server_url = config.token_server_url;
+        #[cfg(feature = "browserid")]
+        let audience = config
+            .audience
+            .unwrap_or_else(|| format!("{}/", token_server_url.origin().ascii_serialization()));
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .user_agent("reqwest (pass-fxa)")
+                //.proxy(reqwest::Proxy::all("http://localhost:8080").unwrap())
+                //.danger_accept_invalid_certs(true)
+                .build()?,
+            base_uri: config.auth_url.as_str().trim_end_matches('/').to_string(),
+            token_server_url: token_server_url.into(),
+            #[cfg(feature = "browserid")]
+            audience,
+            totp: config.totp,
+            unblock_code: config.unblock_code,
+            email_confirmation: config.email_confirmation,
+        })
     }
 
     // TODO: move this crypto stuff somewhere else
-    async fn get_sync_client(self, email: &str, password: &str) -> SyncClient {
+    async fn get_sync_client(self, email: &str, password: &str) -> Result<SyncClient> {
         let email_salt = kwe("quickStretch", email);
         let mut quick_stretched_pw = [0u8; 32];
         pbkdf2::pbkdf2::<Hmac<Sha256>>(
@@ -336,66 +1225,58 @@ impl FxaClient {
         );
         let mut auth_pw = [0u8; 32];
         let quick_stretched_pw_hdkf = Hkdf::<Sha256>::new(None, &quick_stretched_pw);
-        quick_stretched_pw_hdkf
-            .expand(kw("authPW").as_bytes(), &mut auth_pw)
-            .unwrap();
+        quick_stretched_pw_hdkf.expand(kw("authPW").as_bytes(), &mut auth_pw)?;
 
         let mut unwrap_b_key = [0u8; 32];
-        quick_stretched_pw_hdkf
-            .expand(kw("unwrapBkey").as_bytes(), &mut unwrap_b_key)
-            .unwrap();
+        quick_stretched_pw_hdkf.expand(kw("unwrapBkey").as_bytes(), &mut unwrap_b_key)?;
 
         let auth_pwd_hex = hex::encode(auth_pw);
 
         let account_login_response = match self.account_login(email, &auth_pwd_hex, None).await {
             Ok(account_login_response) => account_login_response,
-            Err(bad_request_error) => {
-                match bad_request_error.errno {
-                    125 => {
-                        assert!(bad_request_error.verification_method.is_some());
-                        self.account_login_send_unblock_code(email).await;
-                        print!("A verification code sent to {}: ", email);
-                        io::stdout().flush().unwrap();
-                        let mut unblock_code = String::new();
-                        std::io::stdin().read_line(&mut unblock_code).unwrap();
-                        self.account_login(
-                            email,
-                            &auth_pwd_hex,
-                            Some(Verification::EmailCaptcha(unblock_code.trim())),
-                        )
-                        // TODO move error handling into the api code instead.
-                        .await
-                        .unwrap_or_else(|bad_request_error| match bad_request_error.errno {
-                            127 => {
-                                println!("{}", bad_request_error.message);
-                                panic!();
-                            }
-                            _ => unimplemented!(),
-                        })
-                    }
-                    127 => {
-                        // Cannot have "Invalid unblock code" when no unblock code is given
-                        unreachable!();
-                    }
-                    _ => unimplemented!(),
-                }
+            // Sign-in is blocked until an unblock code sent by email is given
+            Err(Error::Fxa { errno: 125, .. }) => {
+                let callback = self
+                    .unblock_code
+                    .as_ref()
+                    .ok_or(Error::UnblockCodeRequired)?;
+                self.account_login_send_unblock_code(email).await?;
+                let unblock_code = (callback.0)(email);
+                self.account_login(
+                    email,
+                    &auth_pwd_hex,
+                    Some(Verification::EmailCaptcha(unblock_code.trim())),
+                )
+                .await?
             }
+            Err(err) => return Err(err),
         };
 
+        let mut waiting_for_confirmation = false;
         if let Some(ref verification_method) = account_login_response.verification_method {
             match verification_method.as_str() {
-                "email" => println!("Please confirm sign-in by email at {}", email),
-                _ => unimplemented!(),
+                "email" => {
+                    match &self.email_confirmation {
+                        Some(callback) => (callback.0)(email),
+                        None => info!("Waiting for the sign-in to be confirmed by email"),
+                    }
+                    waiting_for_confirmation = true;
+                }
+                "totp-2fa" => {
+                    self.session_verify_totp(&account_login_response.session_token)
+                        .await?
+                }
+                _ => {
+                    return Err(Error::UnsupportedVerification(
+                        verification_method.to_string(),
+                    ))
+                }
             }
         }
 
         let mut derived_key_fetch_token = [0u8; 96];
-        Hkdf::<Sha256>::new(
-            None,
-            &hex::decode(account_login_response.key_fetch_token).unwrap(),
-        )
-        .expand(kw("keyFetchToken").as_bytes(), &mut derived_key_fetch_token)
-        .unwrap();
+        Hkdf::<Sha256>::new(None, &hex::decode(account_login_response.key_fetch_token)?)
+            .expand(kw("keyFetchToken").as_bytes(), &mut derived_key_fetch_token)?;
 
         let token_id = &derived_key_fetch_token[0..32];
         let req_hmac_key = &derived_key_fetch_token[32..64];
@@ -403,371 +1284,619 @@ impl FxaClient {
 
         let hawk_credentials = hawk::Credentials {
             id: hex::encode(token_id),
-            key: hawk::Key::new(req_hmac_key, hawk::DigestAlgorithm::Sha256).unwrap(),
+            key: hawk::Key::new(req_hmac_key, hawk::DigestAlgorithm::Sha256)?,
         };
 
         let bundle = hex::decode(loop {
             match self.account_keys(&hawk_credentials).await {
                 Ok(account_keys) => break account_keys.bundle,
-                Err(_) => {
-                    if account_login_response.verification_method.is_none() {
-                        unimplemented!()
-                    } else {
-                        sleep(Duration::from_millis(500)).await
-                    }
+                // Keys are only available once the sign-in has been confirmed
+                Err(Error::Fxa { .. }) if waiting_for_confirmation => {
+                    sleep(Duration::from_millis(500)).await
                 }
+                Err(err) => return Err(err),
             }
-        })
-        .unwrap();
+        })?;
+        if bundle.len() != 96 {
+            return Err(Error::MalformedRecord(
+                "key bundle must be 96 bytes long".to_string(),
+            ));
+        }
         let ciphertext = &bundle[0..64];
         let mac = &bundle[64..96];
 
         let mut derived_from_key_request_key = [0u8; 96];
-        Hkdf::<Sha256>::new(None, key_request_key)
-            .expand(
-                kw("account/keys").as_bytes(),
-                &mut derived_from_key_request_key,
-            )
-            .unwrap();
+        Hkdf::<Sha256>::new(None, key_request_key).expand(
+            kw("account/keys").as_bytes(),
+            &mut derived_from_key_request_key,
+        )?;
 
-        let mut mac_verifer =
-            Hmac::<Sha256>::new_from_slice(&derived_from_key_request_key[0..32]).unwrap();
+        let mut mac_verifer = Hmac::<Sha256>::new_from_slice(&derived_from_key_request_key[0..32])?;
         mac_verifer.update(ciphertext);
-        mac_verifer
-            .verify(mac)
-            .expect("!!! CRYPTOGRAPHY ERROR SPOOFING IS BEING ATTEMPTED !!!");
+        mac_verifer.verify(mac)?;
 
         xor(&mut derived_from_key_request_key[32..96], ciphertext);
         xor(&mut derived_from_key_request_key[64..96], &unwrap_b_key);
 
         let key_b = &derived_from_key_request_key[64..96];
 
-        let fxa_client_state = hex::encode(&Sha256::new().chain(&key_b).finalize()[0..16]);
+        let fxa_client_state = client_state(key_b);
         // TODO: this can be done concurrently with the previous request
-        let browserid_assertion = &self
-            .get_browserid_assertion(&account_login_response.session_token)
-            .await;
-
         let sync_server = self
-            .sync_server_tokens(&fxa_client_state, &browserid_assertion)
-            .await;
-        let sync_server_credentials = hawk::Credentials {
-            id: sync_server.id,
-            key: hawk::Key::new(sync_server.key.as_bytes(), hawk::DigestAlgorithm::Sha256).unwrap(),
-        };
+            .sync_server_credentials(&account_login_response.session_token, &fxa_client_state)
+            .await?;
 
         let mut sync_key_bundle = [0u8; 64];
-        Hkdf::<Sha256>::new(None, key_b)
-            .expand(kw("oldsync").as_bytes(), &mut sync_key_bundle)
-            .unwrap();
-
-        SyncClient::from_sync_key_bundle(
-            self.client,
-            sync_server.api_endpoint,
-            sync_server_credentials,
-            sync_key_bundle,
+        Hkdf::<Sha256>::new(None, key_b).expand(kw("oldsync").as_bytes(), &mut sync_key_bundle)?;
+
+        SyncClient::from_session(
+            self,
+            Session {
+                email: email.to_string(),
+                session_token: account_login_response.session_token.into(),
+                client_state: fxa_client_state,
+                sync_key_bundle: hex::encode(sync_key_bundle).into(),
+                sync_server,
+            },
         )
         .await
     }
 
+    async fn sync_server_credentials(
+        &self,
+        session_token: &str,
+        client_state: &str,
+    ) -> Result<SyncServerCredentials> {
+        match self
+            .oauth_sync_server_credentials(session_token, client_state)
+            .await
+        {
+            #[cfg(feature = "browserid")]
+            Err(Error::Fxa { .. }) | Err(Error::TokenServer(_)) => {
+                debug!("OAuth failed, falling back to BrowserID");
+                self.browserid_sync_server_credentials(session_token, client_state)
+                    .await
+            }
+            credentials => credentials,
+        }
+    }
+
+    async fn oauth_sync_server_credentials(
+        &self,
+        session_token: &str,
+        client_state: &str,
+    ) -> Result<SyncServerCredentials> {
+        let credentials = session_token_credentials(session_token)?;
+        let key_rotation_timestamp = self
+            .account_scoped_key_data(&credentials)
+            .await?
+            .key_rotation_timestamp;
+        let access_token = self.oauth_token(&credentials).await?.access_token;
+
+        let key_id = key_id(key_rotation_timestamp, client_state)?;
+        Ok(self
+            .sync_server_tokens(&format!("Bearer {}", access_token), "X-KeyID", &key_id)
+            .await?
+            .into())
+    }
+
+    async fn account_scoped_key_data(
+        &self,
+        credentials: &hawk::Credentials,
+    ) -> Result<ScopedKeyData> {
+        let mut request = self
+            .client
+            .post(format!("{}/account/scoped-key-data", self.base_uri))
+            .json(&ScopedKeyDataRequest {
+                client_id: OAUTH_CLIENT_ID,
+                scope: OLDSYNC_SCOPE,
+            })
+            .build()?;
+        hawk_authenticate(&mut request, credentials)?;
+        let mut scoped_key_data: HashMap<String, ScopedKeyData> =
+            fxa_response(self.client.execute(request).await?).await?;
+        scoped_key_data
+            .remove(OLDSYNC_SCOPE)
+            .ok_or_else(|| Error::MalformedRecord("missing oldsync scoped key".to_string()))
+    }
+
+    async fn oauth_token(&self, credentials: &hawk::Credentials) -> Result<OAuthTokenResponse> {
+        let mut request = self
+            .client
+            .post(format!("{}/oauth/token", self.base_uri))
+            .json(&OAuthTokenRequest {
+                client_id: OAUTH_CLIENT_ID,
+                grant_type: "fxa-credentials",
+                scope: OLDSYNC_SCOPE,
+                access_type: "online",
+                ttl: OAUTH_TOKEN_TTL,
+            })
+            .build()?;
+        hawk_authenticate(&mut request, credentials)?;
+        fxa_response(self.client.execute(request).await?).await
+    }
+
     async fn account_login(
         &self,
         email: &str,
         auth_pw: &str,
         verification: Option<Verification<'_>>,
-    ) -> Result<AccountLoginResponse, BadRequestError> {
+    ) -> Result<AccountLoginResponse> {
         let response = self
             .client
             .post(format!("{}/account/login?keys=true", self.base_uri))
             .json(&AccountLoginRequest::new(email, auth_pw, verification))
             .send()
-            .await
-            .unwrap();
-        if response.status() == StatusCode::BAD_REQUEST {
-            Err(response.json::<BadRequestError>().await.unwrap())
-        } else {
-            Ok(response.json::<AccountLoginResponse>().await.unwrap())
-        }
+            .await?;
+        fxa_response(response).await
     }
 
-    async fn account_login_send_unblock_code(&self, email: &str) {
+    async fn account_login_send_unblock_code(&self, email: &str) -> Result<()> {
         let response = self
             .client
             .post(format!("{}/account/login/send_unblock_code", self.base_uri))
             .json(&SendUnblockCodeRequest { email })
             .send()
-            .await
-            .unwrap();
-        match response.status() {
-            StatusCode::OK => {}
-            StatusCode::TOO_MANY_REQUESTS => {
-                println!(
-                    "Too many requests! Try again in {} seconds.",
-                    std::str::from_utf8(response.headers().get("retry-after").unwrap().as_bytes())
-                        .unwrap()
-                );
-                // TODO proper exit
-                panic!("TODO proper ending of stuff");
-            }
-            _ => unimplemented!(),
-        }
+            .await?;
+        fxa_response::<serde_json::Value>(response).await?;
+        Ok(())
     }
 
-    async fn account_keys(
-        &self,
-        credentials: &hawk::Credentials,
-    ) -> Result<AccountKeysResponse, reqwest::Error> {
+    async fn session_verify_totp(&self, session_token: &str) -> Result<()> {
+        let code = match &self.totp {
+            TotpSource::Missing => return Err(Error::TotpRequired),
+            TotpSource::Callback(callback) => callback(),
+            TotpSource::Secret(otp_auth) => otp_auth.generate(unix_time()),
+        };
+
         let mut request = self
             .client
-            .get(format!("{}/account/keys", self.base_uri))
-            .build()
-            .unwrap();
-        hawk_authenticate(&mut request, credentials);
-        self.client.execute(request).await.unwrap().json().await
+            .post(format!("{}/session/verify/totp", self.base_uri))
+            .json(&VerifyTotpRequest { code: code.trim() })
+            .build()?;
+        hawk_authenticate(&mut request, &session_token_credentials(session_token)?)?;
+        let response: VerifyTotpResponse =
+            fxa_response(self.client.execute(request).await?).await?;
+        if response.success {
+            Ok(())
+        } else {
+            Err(Error::InvalidTotpCode)
+        }
     }
 
-    async fn certificate_sign(
-        &self,
-        n: &str,
-        e: &str,
-        credentials: &hawk::Credentials,
-    ) -> (CertificateSignResponse, u64) {
-        let url = Url::parse(&format!("{}/certificate/sign", self.base_uri)).unwrap();
+    async fn account_keys(&self, credentials: &hawk::Credentials) -> Result<AccountKeysResponse> {
         let mut request = self
             .client
-            .post(url.clone())
-            .json(&CertificateSignRequest {
-                public_key: PublicKey {
-                    algorithm: "RS",
-                    n,
-                    e,
-                },
-                // Value in milliseconds
-                duration: DURATION * 1000,
-            })
-            .build()
-            .unwrap();
-        hawk_authenticate(&mut request, credentials);
-        let response = self.client.execute(request).await.unwrap();
-        let server_time = response
-            .headers()
-            .get("timestamp")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .parse()
-            .unwrap();
-
-        (response.json().await.unwrap(), server_time)
+            .get(format!("{}/account/keys", self.base_uri))
+            .build()?;
+        hawk_authenticate(&mut request, credentials)?;
+        fxa_response(self.client.execute(request).await?).await
     }
 
+    /// Exchange the `authorization` for sync server credentials, `key_header`
+    /// identifies the sync keys used by the client.
     async fn sync_server_tokens(
         &self,
-        client_state: &str,
-        browserid_assertion: &str,
-    ) -> SyncServerToken {
-        self.client
-            .get("https://token.services.mozilla.com/1.0/sync/1.5")
-            .header(
-                header::AUTHORIZATION,
-                format!("BrowserID {}", browserid_assertion),
-            )
-            .header("X-Client-State", client_state)
+        authorization: &str,
+        key_header: &str,
+        key: &str,
+    ) -> Result<SyncServerToken> {
+        let response = self
+            .client
+            .get(&self.token_server_url)
+            .header(header::AUTHORIZATION, authorization)
+            .header(key_header, key)
             .send()
-            .await
-            .unwrap()
-            .json()
-            .await
-            .unwrap()
+            .await?;
+        if !response.status().is_success() {
+            return Err(Error::TokenServer(response.status()));
+        }
+        Ok(response.json().await?)
     }
+}
 
-    async fn _info_collections(&self, sync_server_endpoint: &str, credentials: &hawk::Credentials) {
-        let mut request = self
-            .client
-            .get(format!("{}/info/collections", sync_server_endpoint))
-            .build()
-            .unwrap();
-        hawk_authenticate(&mut request, credentials);
-        self.client.execute(request).await.unwrap();
-    }
-
-    async fn get_browserid_assertion(&self, session_token: &str) -> String {
-        let mut derived_from_session_token = [0u8; 64];
-        println!("Generating RSA Private Key. This may take a while.");
-        let rsa_private_key = RSAPrivateKey::new(&mut OsRng, 2048).unwrap();
-        Hkdf::<Sha256>::new(None, &hex::decode(session_token).unwrap())
-            .expand(
-                kw("sessionToken").as_bytes(),
-                &mut derived_from_session_token,
-            )
-            .unwrap();
-        let (certificate, server_time) = self
-            .certificate_sign(
-                &rsa_private_key.n().to_str_radix(10),
-                &rsa_private_key.e().to_str_radix(10),
-                &hawk::Credentials {
-                    id: hex::encode(&derived_from_session_token[0..32]),
-                    key: hawk::Key::new(
-                        &derived_from_session_token[32..64],
-                        hawk::DigestAlgorithm::Sha256,
-                    )
-                    .unwrap(),
-                },
-            )
-            .await;
-
-        let signed_data = format!(
-            "{}.{}",
-            base64::encode_config("{\"alg\": \"RS256\"}", base64::URL_SAFE_NO_PAD),
-            base64::encode_config(
-                &serde_json::to_string(&Assertion {
-                    exp: (server_time + DURATION) * 1000,
-                    aud: "https://token.services.mozilla.com/"
-                })
-                .unwrap(),
-                base64::URL_SAFE_NO_PAD
-            ),
-        );
+#[derive(Deserialize)]
+struct BatchCollectionResponse {
+    success: Vec<String>,
+    failed: HashMap<String, FailureReason>,
+    batch: Option<String>,
+    /// New last-modified time of the collection
+    modified: Option<f64>,
+}
 
-        let assertion = format!(
-            "{}.{}",
-            &signed_data,
-            base64::encode_config(
-                rsa_private_key
-                    .sign(
-                        PaddingScheme::PKCS1v15Sign {
-                            hash: Some(Hash::SHA2_256),
-                        },
-                        &Sha256::new().chain(&signed_data).finalize(),
-                    )
-                    .unwrap(),
-                base64::URL_SAFE_NO_PAD,
-            )
+/// Why the server didn't store a record, as one or several messages.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FailureReason {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<FailureReason> for String {
+    fn from(reason: FailureReason) -> Self {
+        match reason {
+            FailureReason::One(reason) => reason,
+            FailureReason::Many(reasons) => reasons.join(", "),
+        }
+    }
+}
+
+/// Records stored and rejected by the server during an upload.
+#[derive(Debug, Default)]
+pub struct UploadReport {
+    /// IDs of the records the server stored
+    pub succeeded: Vec<String>,
+    /// IDs of the records the server rejected, with its reason
+    pub failed: BTreeMap<String, String>,
+}
+
+impl UploadReport {
+    fn add(&mut self, response: BatchCollectionResponse) {
+        self.succeeded.extend(response.success);
+        self.failed.extend(
+            response
+                .failed
+                .into_iter()
+                .map(|(id, reason)| (id, reason.into())),
         );
+    }
 
-        format!("{}~{}", certificate.cert, assertion)
+    /// Add the report of another upload, that retried the failed records.
+    pub fn merge(&mut self, other: UploadReport) {
+        self.succeeded.extend(other.succeeded);
+        self.failed = other.failed;
     }
 }
 
 #[derive(Deserialize)]
-struct BatchCollectionResponse {
-    success: Vec<String>,
-    // FIXME: These are individual attributes
-    failed: HashMap<String, String>,
-    batch: Option<String>,
+struct DeleteResponse {
+    modified: f64,
 }
 
 impl SyncClient {
-    pub async fn new(email: &str, password: &str) -> Self {
-        FxaClient::new().get_sync_client(email, password).await
+    /// Log in to FxA using Mozilla's production servers.
+    pub async fn new(email: &str, password: &str) -> Result<Self> {
+        SyncClientBuilder::new().login(email, password).await
     }
 
-    async fn from_sync_key_bundle(
-        http_client: reqwest::Client,
-        api_endpoint: String,
-        sync_server_credentials: hawk::Credentials,
-        sync_key_bundle: [u8; 64],
-    ) -> Self {
+    /// Configure the servers to use before logging in.
+    pub fn builder() -> SyncClientBuilder {
+        SyncClientBuilder::new()
+    }
+
+    async fn from_session(fxa_client: FxaClient, session: Session) -> Result<Self> {
         let sync = Self {
-            http_client,
-            api_endpoint,
-            sync_server_credentials,
-            key_bundle: sync_key_bundle,
+            http_client: fxa_client.client.clone(),
+            fxa_client,
+            api_endpoint: session.sync_server.api_endpoint.clone(),
+            key_bundle: session.sync_key_bundle()?,
+            collection_key_bundles: HashMap::new(),
+            meta_global: None,
+            session: Mutex::new(session),
+            last_modified: Mutex::default(),
+            configuration: ServerConfiguration::default(),
+            alert: Mutex::default(),
+            backoff: Mutex::default(),
         };
 
-        let plaintext: CryptoKeyRecord = sync.get_storage_object("crypto/keys").await;
-        let mut bulk_key_bundle = [0u8; 64];
-        base64::decode_config_slice(
-            &plaintext.default[0],
-            base64::STANDARD,
-            &mut bulk_key_bundle,
-        )
-        .unwrap();
-        base64::decode_config_slice(
-            &plaintext.default[1],
-            base64::STANDARD,
-            &mut bulk_key_bundle[32..64],
-        )
-        .unwrap();
-
-        SyncClient {
-            key_bundle: bulk_key_bundle,
+        let crypto_keys: CryptoKeyRecord = sync.get_storage_object("crypto/keys").await?;
+        Ok(SyncClient {
+            key_bundle: decode_key_bundle(&crypto_keys.default)?,
+            collection_key_bundles: crypto_keys
+                .collections
+                .iter()
+                .map(|(collection, keys)| Ok((collection.clone(), decode_key_bundle(keys)?)))
+                .collect::<Result<_>>()?,
+            meta_global: sync.get_meta_global().await?,
+            configuration: sync.get_configuration().await?,
             ..sync
+        })
+    }
+
+    /// The session used by this client, to be restored later with
+    /// [`SyncClientBuilder::restore`].
+    ///
+    /// The sync server credentials it contains are renewed when they expire,
+    /// so it may change while the client is used.
+    pub fn session(&self) -> Session {
+        self.session.lock().unwrap().clone()
+    }
+
+    /// Get new sync server credentials from the token server.
+    async fn refresh_credentials(&self) -> Result<()> {
+        let (session_token, client_state) = {
+            let session = self.session.lock().unwrap();
+            (session.session_token.clone(), session.client_state.clone())
+        };
+        debug!("Requesting new sync server credentials");
+        let sync_server = self
+            .fxa_client
+            .sync_server_credentials(session_token.unsecure(), &client_state)
+            .await?;
+        if sync_server.api_endpoint != self.api_endpoint {
+            return Err(Error::NodeReassigned);
         }
+        self.session.lock().unwrap().sync_server = sync_server;
+        Ok(())
     }
 
-    async fn hawk_execute(
-        &self,
-        mut request: Request,
-    ) -> Result<reqwest::Response, reqwest::Error> {
-        hawk_authenticate(&mut request, &self.sync_server_credentials);
-        self.http_client.execute(request).await
+    /// Hawk credentials for the sync server, renewed if they are about to
+    /// expire.
+    async fn hawk_credentials(&self) -> Result<hawk::Credentials> {
+        let expired = self.session.lock().unwrap().sync_server.is_expired();
+        if expired {
+            self.refresh_credentials().await?;
+        }
+        self.session.lock().unwrap().sync_server.hawk_credentials()
+    }
+
+    fn last_modified(&self, collection: &str) -> Option<f64> {
+        self.last_modified.lock().unwrap().get(collection).copied()
+    }
+
+    fn set_last_modified(&self, collection: &str, last_modified: f64) {
+        self.last_modified
+            .lock()
+            .unwrap()
+            .insert(collection.to_string(), last_modified);
+    }
+
+    /// Send `X-If-Unmodified-Since` with the last-modified time of `collection`,
+    /// if it was seen.
+    fn if_unmodified_since(&self, request: RequestBuilder, collection: &str) -> RequestBuilder {
+        match self.last_modified(collection) {
+            Some(last_modified) => {
+                request.header("X-If-Unmodified-Since", format!("{:.2}", last_modified))
+            }
+            None => request,
+        }
+    }
+
+    /// Alert sent by the sync server, such as a notice of its end of life.
+    pub fn alert(&self) -> Option<String> {
+        self.alert.lock().unwrap().clone()
+    }
+
+    /// Seconds the sync server asked clients not to sync for, once done.
+    pub fn backoff(&self) -> Option<u64> {
+        *self.backoff.lock().unwrap()
+    }
+
+    /// Send `request`, waiting and sending it again while the server is
+    /// unavailable for a short time.
+    async fn hawk_execute(&self, mut request: Request) -> Result<reqwest::Response> {
+        let mut attempt = 1;
+        let mut refreshed = false;
+        loop {
+            // Requests with a streamed body can't be sent again
+            let next_request = request.try_clone();
+            hawk_authenticate(&mut request, &self.hawk_credentials().await?)?;
+            let response = self.http_client.execute(request).await?;
+
+            // The credentials may have been revoked before they expired
+            if response.status() == StatusCode::UNAUTHORIZED && !refreshed {
+                if let Some(next_request) = next_request {
+                    self.refresh_credentials().await?;
+                    refreshed = true;
+                    request = next_request;
+                    continue;
+                }
+            }
+
+            if let Some(alert) = response.headers().get("X-Weave-Alert") {
+                let alert = String::from_utf8_lossy(alert.as_bytes()).into_owned();
+                warn!("Sync server alert: {}", alert);
+                *self.alert.lock().unwrap() = Some(alert);
+            }
+            if let Some(backoff) = header_seconds(&response, "X-Weave-Backoff") {
+                let mut current = self.backoff.lock().unwrap();
+                *current = current.max(Some(backoff));
+            }
+            if !is_unavailable(response.status()) {
+                return Ok(response);
+            }
+
+            let retry_after = retry_after(&response);
+            let wait = retry_after.unwrap_or(1 << attempt);
+            match next_request {
+                Some(next_request) if attempt < MAX_REQUEST_ATTEMPTS && wait <= MAX_RETRY_WAIT => {
+                    debug!(
+                        "Sync server responded with {}, retrying in {} seconds",
+                        response.status(),
+                        wait
+                    );
+                    sleep(Duration::from_secs(wait)).await;
+                    request = next_request;
+                    attempt += 1;
+                }
+                _ => return Err(Error::Unavailable { retry_after }),
+            }
+        }
     }
 
-    async fn get_storage_object<T>(&self, object: impl AsRef<str>) -> T
+    /// Download and decrypt a single object, such as `crypto/keys`.
+    pub async fn get_storage_object<T>(&self, object: impl AsRef<str>) -> Result<T>
     where
         T: de::DeserializeOwned,
     {
-        let decrypted_payload = self
+        let response = self
             .hawk_execute(
                 self.http_client
                     .get(format!("{}/storage/{}", self.api_endpoint, object.as_ref()))
-                    .build()
-                    .unwrap(),
+                    .build()?,
             )
-            .await
-            .unwrap()
-            .json::<BSO>()
-            .await
-            .unwrap()
-            .decrypt_payload(&self.key_bundle[0..32], &self.key_bundle[32..64]);
-        serde_json::from_slice(&decrypted_payload).unwrap()
+            .await?;
+        let collection = object.as_ref().split('/').next().unwrap_or_default();
+        let key_bundle = self.key_bundle(collection);
+        let decrypted_payload = sync_response::<BSO>(response)
+            .await?
+            .decrypt_payload(&key_bundle[0..32], &key_bundle[32..64])?;
+        Ok(serde_json::from_slice(&decrypted_payload)?)
     }
 
-    pub async fn get_collection(&self, collection: &str) -> Vec<String> {
-        self.hawk_execute(
-            self.http_client
-                .get(format!("{}/storage/{}", self.api_endpoint, collection))
-                .build()
-                .unwrap(),
-        )
-        .await
-        .unwrap()
-        .json()
-        .await
-        .unwrap()
+    pub async fn get_collection(&self, collection: &str) -> Result<Vec<String>> {
+        let response = self
+            .hawk_execute(
+                self.http_client
+                    .get(format!("{}/storage/{}", self.api_endpoint, collection))
+                    .build()?,
+            )
+            .await?;
+        sync_response(response).await
     }
 
-    pub async fn get_logins(&self) -> Vec<Login> {
-        let mut passwords = Vec::new();
-        let password_bsos = self
-            .get_collection("passwords")
-            .await
-            .iter()
-            .map(|password_id| self.get_storage_object(format!("passwords/{}", password_id)))
-            .collect::<FuturesUnordered<_>>();
-        let passwords_len = password_bsos.len();
-        let mut password_bsos_enumerate = password_bsos.enumerate();
-        let mut stdout = io::stdout();
-        eprint!("[0/{}] Downloading passwords", passwords_len);
-        stdout.flush().unwrap();
-        while let Some((i, password_bso)) = password_bsos_enumerate.next().await {
-            if let PasswordBSORecord::Password(password) = password_bso {
-                passwords.push(password);
+    /// Download one page of full records from `collection` modified after
+    /// `newer`, starting at `offset`.
+    async fn get_bsos(
+        &self,
+        collection: &str,
+        newer: Option<f64>,
+        offset: Option<&str>,
+    ) -> Result<BsoPage> {
+        let mut query = vec![
+            ("full", "1".to_string()),
+            ("limit", FETCH_LIMIT.to_string()),
+        ];
+        if let Some(newer) = newer {
+            query.push(("newer", format!("{:.2}", newer)));
+        }
+        if let Some(offset) = offset {
+            query.push(("offset", offset.to_string()));
+        }
+        let response = self
+            .hawk_execute(
+                self.http_client
+                    .get(format!("{}/storage/{}", self.api_endpoint, collection))
+                    .query(&query)
+                    .build()?,
+            )
+            .await?;
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let next_offset = header("X-Weave-Next-Offset");
+        let last_modified = header("X-Last-Modified").and_then(|value| value.parse().ok());
+        Ok(BsoPage {
+            bsos: sync_response(response).await?,
+            next_offset,
+            last_modified,
+        })
+    }
+
+    /// Access the records of the `name` collection as objects of type `T`.
+    pub fn collection<'a, T>(&'a self, name: &'a str) -> Collection<'a, T>
+    where
+        T: BsoObject + de::DeserializeOwned,
+    {
+        Collection::new(self, name)
+    }
+
+    pub fn passwords(&self) -> Collection<'_, Login> {
+        self.collection("passwords")
+    }
+
+    /// Download and decrypt the whole passwords collection.
+    pub async fn get_logins(&self) -> Result<Vec<Login>> {
+        let mut cache = LoginCache::default();
+        self.update_logins(&mut cache).await?;
+        Ok(cache.logins())
+    }
+
+    /// Bring `cache` up to date with the passwords collection, only
+    /// downloading the records modified since it was last updated.
+    pub async fn update_logins(&self, cache: &mut LoginCache) -> Result<()> {
+        let sync_id = self.engine_sync_id("passwords");
+        if cache.api_endpoint != self.api_endpoint || cache.sync_id.as_deref() != sync_id {
+            *cache = LoginCache {
+                api_endpoint: self.api_endpoint.clone(),
+                sync_id: sync_id.map(str::to_string),
+                ..LoginCache::default()
+            };
+        }
+
+        let changes = self.passwords().fetch_since(cache.last_modified).await?;
+        if changes.reset {
+            cache.logins.clear();
+        }
+        for (modified, record) in changes.records {
+            match record {
+                Record::Object(login) => {
+                    cache
+                        .logins
+                        .insert(login.id.clone(), CachedLogin { modified, login });
+                }
+                Record::Tombstone(tombstone) => {
+                    cache.logins.remove(tombstone.id());
+                }
             }
-            eprint!("\r[{}/{}] Downloading passwords", i + 1, passwords_len);
-            stdout.flush().unwrap();
         }
-        eprintln!();
-        passwords
+        cache.last_modified = changes.last_modified;
+        Ok(())
+    }
+
+    async fn get_meta_global(&self) -> Result<Option<MetaGlobal>> {
+        let response = self
+            .hawk_execute(
+                self.http_client
+                    .get(format!("{}/storage/meta/global", self.api_endpoint))
+                    .build()?,
+            )
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(
+            sync_response::<MetaGlobalBso>(response).await?.payload,
+        ))
+    }
+
+    /// Fail unless records of `collection` can be written to the storage.
+    fn check_writable(&self, collection: &str) -> Result<()> {
+        match &self.meta_global {
+            Some(meta_global) => meta_global.check_writable(collection),
+            None => Err(Error::UnsupportedStorage(
+                "storage without meta/global".to_string(),
+            )),
+        }
+    }
+
+    /// Sync ID of the `collection` engine, if it's enabled.
+    fn engine_sync_id(&self, collection: &str) -> Option<&str> {
+        Some(&self.meta_global.as_ref()?.engines.get(collection)?.sync_id)
+    }
+
+    fn key_bundle(&self, collection: &str) -> &[u8; 64] {
+        self.collection_key_bundles
+            .get(collection)
+            .unwrap_or(&self.key_bundle)
+    }
+
+    /// Limits of the server, or the defaults if it doesn't advertise them.
+    async fn get_configuration(&self) -> Result<ServerConfiguration> {
+        let response = self
+            .hawk_execute(
+                self.http_client
+                    .get(format!("{}/info/configuration", self.api_endpoint))
+                    .build()?,
+            )
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(ServerConfiguration::default());
+        }
+        sync_response(response).await
     }
 
     async fn post_collection(
         &self,
-        objects: &[impl Serialize + BsoObject],
+        collection: &str,
+        bsos: &[BSO],
         batch: Option<&str>,
         commit: bool,
-    ) -> BatchCollectionResponse {
+    ) -> Result<BatchCollectionResponse> {
         let mut query = Vec::new();
         if commit {
             query.push(("commit", "true"));
@@ -775,82 +1904,197 @@ impl SyncClient {
         if let Some(batch) = batch {
             query.push(("batch", batch));
         }
-        self.hawk_execute(
-            self.http_client
-                .post(format!("{}/storage/passwords", self.api_endpoint))
-                .json(
-                    &objects
-                        .iter()
-                        .map(|login| {
-                            BSO::from_object(
-                                login,
-                                &self.key_bundle[0..32],
-                                &self.key_bundle[32..64],
-                            )
-                        })
-                        .collect::<Vec<_>>(),
+        let response = self
+            .hawk_execute(
+                self.if_unmodified_since(
+                    self.http_client
+                        .post(format!("{}/storage/{}", self.api_endpoint, collection)),
+                    collection,
                 )
+                .json(&bsos)
                 .query(&query)
-                .build()
-                .unwrap(),
-        )
-        .await
-        .unwrap()
-        .json()
-        .await
-        .unwrap()
-    }
-
-    async fn upload_collection(&self, objects: &[impl BsoObject + Serialize]) {
-        if objects.len() > BATCH_SIZE {
-            let mut chunks = objects.chunks(BATCH_SIZE).enumerate();
-            let number_of_chunks = chunks.len();
-            let batch_id = self
-                .post_collection(chunks.next().unwrap().1, Some("true"), false)
-                .await
-                .batch
-                .unwrap();
-            for (i, chunk) in chunks {
-                let commit = i == number_of_chunks - 1;
-                // TODO deal with the failed BSOs, to retry them
-                self.post_collection(chunk, Some(&batch_id), commit).await;
+                .build()?,
+            )
+            .await?;
+        let response: BatchCollectionResponse = sync_response(response).await?;
+        if let Some(modified) = response.modified {
+            self.set_last_modified(collection, modified);
+        }
+        Ok(response)
+    }
+
+    async fn upload_collection(
+        &self,
+        collection: &str,
+        objects: &[impl BsoObject + Serialize],
+    ) -> Result<UploadReport> {
+        self.check_writable(collection)?;
+        let key_bundle = self.key_bundle(collection);
+        let bsos = objects
+            .iter()
+            .map(|object| BSO::from_object(object, &key_bundle[0..32], &key_bundle[32..64]))
+            .collect::<Result<Vec<_>>>()?;
+        let sizes = bsos
+            .iter()
+            .map(|bso| Ok(serde_json::to_vec(bso)?.len()))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut report = UploadReport::default();
+        // Until the server shows it doesn't support batches
+        let mut batching = true;
+        for batch in self.configuration.split(&sizes) {
+            let mut posts = batch.into_iter();
+            if !batching || posts.len() == 1 {
+                for post in posts {
+                    report.add(
+                        self.post_collection(collection, &bsos[post], None, false)
+                            .await?,
+                    );
+                }
+                continue;
+            }
+
+            let response = self
+                .post_collection(
+                    collection,
+                    &bsos[posts.next().unwrap()],
+                    Some("true"),
+                    false,
+                )
+                .await?;
+            let batch_id = response.batch.clone();
+            report.add(response);
+            match batch_id {
+                Some(batch_id) => {
+                    let number_of_posts = posts.len();
+                    for (i, post) in posts.enumerate() {
+                        let commit = i == number_of_posts - 1;
+                        report.add(
+                            self.post_collection(collection, &bsos[post], Some(&batch_id), commit)
+                                .await?,
+                        );
+                    }
+                }
+                None => {
+                    // The records were stored right away
+                    batching = false;
+                    for post in posts {
+                        report.add(
+                            self.post_collection(collection, &bsos[post], None, false)
+                                .await?,
+                        );
+                    }
+                }
             }
-        } else {
-            self.post_collection(objects, None, false).await;
         }
+        Ok(report)
     }
 
-    pub async fn put_logins(&self, logins: &[Login]) {
-        self.upload_collection(logins).await;
+    /// Upload `objects`, uploading the ones the server rejected again with an
+    /// increasing delay.
+    async fn upload_with_retries<T: BsoObject + Serialize>(
+        &self,
+        collection: &str,
+        objects: &[T],
+    ) -> Result<UploadReport> {
+        let mut report = self.upload_collection(collection, objects).await?;
+        for retry in 0..UPLOAD_RETRIES {
+            if report.failed.is_empty() {
+                break;
+            }
+            let failed: Vec<_> = objects
+                .iter()
+                .filter(|object| report.failed.contains_key(object.id()))
+                .collect();
+            debug!("Uploading {} rejected records again", failed.len());
+            sleep(Duration::from_secs(1 << retry)).await;
+            report.merge(self.upload_collection(collection, &failed).await?);
+        }
+        Ok(report)
     }
 
-    pub async fn delete_objects(self, ids: &[&str]) {
-        self.upload_collection(
-            &ids.iter()
-                .map(|id| Deleted {
-                    id: id.to_string(),
-                    deleted: true,
-                })
-                .collect::<Vec<_>>(),
-        )
-        .await;
+    pub async fn put_logins(&self, logins: &[Login]) -> Result<UploadReport> {
+        self.passwords().upload(logins).await
     }
 
-    pub async fn delete_ids(&self, collection: &str, ids: &[&str]) {
-        for chunk in ids.chunks(BATCH_SIZE) {
-            self.hawk_execute(
-                self.http_client
-                    .delete(format!("{}/storage/{}", self.api_endpoint, collection))
+    pub async fn delete_objects(&self, ids: &[&str]) -> Result<UploadReport> {
+        self.passwords().delete(ids).await
+    }
+
+    pub async fn delete_ids(&self, collection: &str, ids: &[&str]) -> Result<()> {
+        self.check_writable(collection)?;
+        for chunk in ids.chunks(MAX_DELETE_IDS) {
+            let response = self
+                .hawk_execute(
+                    self.if_unmodified_since(
+                        self.http_client
+                            .delete(format!("{}/storage/{}", self.api_endpoint, collection)),
+                        collection,
+                    )
                     .query(&[("ids", &chunk.join(","))])
-                    .build()
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
+                    .build()?,
+                )
+                .await?;
+            let response: DeleteResponse = sync_response(response).await?;
+            self.set_last_modified(collection, response.modified);
         }
+        Ok(())
     }
 }
 
+/// One page of a collection download.
+struct BsoPage {
+    bsos: Vec<BSO>,
+    /// Offset to request the next page with, if there is one
+    next_offset: Option<String>,
+    /// Last-modified time of the collection
+    last_modified: Option<f64>,
+}
+
+/// Decrypt `bsos` one by one as the iterator is consumed, along with the time
+/// they were last modified.
+fn decrypt_records<'a, T: de::DeserializeOwned>(
+    bsos: Vec<BSO>,
+    key_bundle: &'a [u8; 64],
+) -> impl Iterator<Item = Result<(f64, T)>> + 'a {
+    bsos.into_iter().map(move |bso| {
+        let decrypted_payload = bso.decrypt_payload(&key_bundle[0..32], &key_bundle[32..64])?;
+        Ok((bso.modified, serde_json::from_slice(&decrypted_payload)?))
+    })
+}
+
+/// Hex client state sent to the token server, derived from `kB`.
+fn client_state(key_b: &[u8]) -> String {
+    hex::encode(&Sha256::new().chain(key_b).finalize()[0..16])
+}
+
+/// Key ID sent along OAuth tokens to the token server, which replaces
+/// X-Client-State, see
+/// https://mozilla-services.readthedocs.io/en/latest/token/apis.html
+fn key_id(key_rotation_timestamp: u64, client_state: &str) -> Result<String> {
+    Ok(format!(
+        "{}-{}",
+        key_rotation_timestamp,
+        base64::encode_config(hex::decode(client_state)?, base64::URL_SAFE_NO_PAD)
+    ))
+}
+
+/// Hawk credentials for requests authenticated with the session token.
+fn session_token_credentials(session_token: &str) -> Result<hawk::Credentials> {
+    let mut derived_from_session_token = [0u8; 64];
+    Hkdf::<Sha256>::new(None, &hex::decode(session_token)?).expand(
+        kw("sessionToken").as_bytes(),
+        &mut derived_from_session_token,
+    )?;
+    Ok(hawk::Credentials {
+        id: hex::encode(&derived_from_session_token[0..32]),
+        key: hawk::Key::new(
+            &derived_from_session_token[32..64],
+            hawk::DigestAlgorithm::Sha256,
+        )?,
+    })
+}
+
 fn xor(a: &mut [u8], b: &[u8]) {
     for (x, y) in a.iter_mut().zip(b.iter()) {
         *x ^= *y;
@@ -865,17 +2109,26 @@ fn kw(name: &str) -> String {
     format!("identity.mozilla.com/picl/v1/{}", name)
 }
 
-#[derive(Serialize)]
-struct Assertion<'a> {
-    exp: u64,
-    aud: &'a str,
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+fn unix_time_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
 }
 
 fn generate_iv() -> [u8; 16] {
     let mut iv = [0u8; 16];
     OsRng.fill_bytes(&mut iv);
-    return iv;
+    iv
 }
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -896,6 +2149,47 @@ mod tests {
         );
     }
 
+    #[test]
+    fn server_urls_test() {
+        let fxa_client = FxaClient::new(SyncClientBuilder::new()).unwrap();
+        #[cfg(feature = "browserid")]
+        assert_eq!(fxa_client.audience, "https://token.services.mozilla.com/");
+        assert_eq!(fxa_client.base_uri, DEFAULT_AUTH_URL);
+
+        let fxa_client = FxaClient::new(
+            SyncClientBuilder::new()
+                .auth_url(Url::parse("https://fxa.example.com/v1/").unwrap())
+                .token_server_url(
+                    Url::parse("https://sync.example.com:8000/token/1.0/sync/1.5").unwrap(),
+                ),
+        )
+        .unwrap();
+        #[cfg(feature = "browserid")]
+        assert_eq!(fxa_client.audience, "https://sync.example.com:8000/");
+        assert_eq!(fxa_client.base_uri, "https://fxa.example.com/v1");
+    }
+
+    #[test]
+    fn session_roundtrip_test() {
+        let session = Session {
+            email: "example@riseup.net".to_string(),
+            session_token: "00".repeat(32).into(),
+            client_state: "00".repeat(16),
+            sync_key_bundle: "ab".repeat(64).into(),
+            sync_server: SyncServerCredentials {
+                id: "id".to_string(),
+                key: "key".into(),
+                api_endpoint: "https://sync.example.com/1.5/1".to_string(),
+                expires_at: unix_time() + 3600,
+            },
+        };
+        let session: Session =
+            serde_json::from_str(&serde_json::to_string(&session).unwrap()).unwrap();
+        assert_eq!(session.email(), "example@riseup.net");
+        assert_eq!(session.sync_key_bundle().unwrap(), [0xab; 64]);
+        assert!(!session.sync_server.is_expired());
+    }
+
     // TODO: add test for only containing URL safe characters
     #[test]
     fn generate_bso_id_test1() {
@@ -904,7 +2198,7 @@ mod tests {
 
     #[test]
     fn parse_bso() {
-        serde_json::from_str::<BSO>(
+        let bso = serde_json::from_str::<BSO>(
             r#"
 {
   "id": "ybhmIXr2Vj9Y",
@@ -913,7 +2207,9 @@ mod tests {
   "sortindex": 1
 }
             "#,
-        ).unwrap();
+        )
+        .unwrap();
+        assert_eq!(bso.modified, 1616761977.69);
     }
 
     #[test]
@@ -932,7 +2228,10 @@ mod tests {
   "timePasswordChanged": 1626895557678
 }
         "#;
-        serde_json::from_str::<PasswordBSORecord>(json).unwrap();
+        assert!(matches!(
+            serde_json::from_str::<Record<Login>>(json).unwrap(),
+            Record::Object(_)
+        ));
     }
 
     #[test]
@@ -959,5 +2258,248 @@ mod tests {
         "#,
         )
         .unwrap();
+        assert_eq!(object.a, "string");
+        assert_eq!(object.b.c, "c_string");
+        assert_eq!(object.b.d, 1234);
+    }
+
+    #[test]
+    fn key_id_test() {
+        let key_b = hex::decode("8b2e1303e21eee06a945683b8d495b9bf079ca30baa37eb8392d9ffa4767be45")
+            .unwrap();
+        let client_state = client_state(&key_b);
+        assert_eq!(client_state, "46061d0ea8f4ed8b553869b1594248a4");
+        assert_eq!(
+            key_id(1510726317123, &client_state).unwrap(),
+            "1510726317123-RgYdDqj07YtVOGmxWUJIpA"
+        );
+    }
+
+    #[test]
+    fn login_unknown_fields_test() {
+        let json = r#"{"id":"{7f3db3a7-ef2d-0446-aad0-049f1b0ff0fa}","hostname":"https://www.reddit.com","formSubmitURL":"","httpRealm":null,"username":"asdf","password":"asdf","usernameField":"","passwordField":"","timeCreated":1626895557678,"timePasswordChanged":1626895557678,"timesUsed":3,"everSynced":true,"unknownFields":{"a":[1,2]}}"#;
+        let login: Login = serde_json::from_str(json).unwrap();
+        assert_eq!(login.times_used, Some(3));
+        assert_eq!(login.unknown_fields["everSynced"], true);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&serde_json::to_string(&login).unwrap())
+                .unwrap(),
+            serde_json::from_str::<serde_json::Value>(json).unwrap()
+        );
+
+        let changed = login.with_password("qwerty");
+        assert_eq!(changed.time_created, Some(1626895557678));
+        assert!(changed.time_password_changed.unwrap() > 1626895557678);
+        assert_eq!(changed.unknown_fields, login.unknown_fields);
+        let unchanged = login.with_password("asdf");
+        assert_eq!(unchanged.time_password_changed, Some(1626895557678));
+    }
+
+    #[test]
+    fn login_builder_test() {
+        let login = Login::builder(
+            "username",
+            "password",
+            Url::parse("https://example.com").unwrap(),
+        )
+        .id("CZtAJxrSHbA0")
+        .http_realm("Admin area")
+        .time_created(1626895557678)
+        .build();
+        assert_eq!(
+            serde_json::to_value(&login).unwrap()["formSubmitURL"],
+            serde_json::Value::Null
+        );
+        assert_eq!(login.http_realm(), Some("Admin area"));
+        assert_eq!(login.time_created(), Some(1626895557678));
+
+        let login = LoginBuilder::from(login)
+            .form_submit_url("https://example.com")
+            .username_field("user")
+            .password_field("pass")
+            .build();
+        assert_eq!(login.id(), "CZtAJxrSHbA0");
+        assert_eq!(login.http_realm(), None);
+        assert_eq!(login.form_submit_url(), Some("https://example.com"));
+        assert_eq!(login.username_field(), "user");
+        assert_eq!(login.password_field(), "pass");
+    }
+
+    #[test]
+    fn bso_roundtrip() {
+        let mut key_bundle = [0u8; 64];
+        OsRng.fill_bytes(&mut key_bundle);
+        let login = Login::new(
+            "username",
+            "password",
+            Url::parse("https://github.com").unwrap(),
+        );
+        let bso = BSO::from_object(&login, &key_bundle[0..32], &key_bundle[32..64]).unwrap();
+        let decrypted: Login = serde_json::from_slice(
+            &bso.decrypt_payload(&key_bundle[0..32], &key_bundle[32..64])
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(decrypted.id(), login.id());
+        assert_eq!(decrypted.password, login.password);
+    }
+
+    #[test]
+    fn decrypt_records_test() {
+        let mut key_bundle = [0u8; 64];
+        OsRng.fill_bytes(&mut key_bundle);
+        let login = Login::new(
+            "username",
+            "password",
+            Url::parse("https://github.com").unwrap(),
+        );
+        let deleted = Tombstone::new("CZtAJxrSHbA0");
+        let bsos = vec![
+            BSO::from_object(&login, &key_bundle[0..32], &key_bundle[32..64]).unwrap(),
+            BSO::from_object(&deleted, &key_bundle[0..32], &key_bundle[32..64]).unwrap(),
+        ];
+        let records = decrypt_records::<Record<Login>>(bsos, &key_bundle)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert!(matches!(
+            &records[..],
+            [(_, Record::Object(decrypted)), (_, Record::Tombstone(_))]
+                if decrypted.id() == login.id()
+        ));
+    }
+
+    #[test]
+    fn upload_report_test() {
+        let response: BatchCollectionResponse = serde_json::from_str(
+            r#"{"success":["a"],"failed":{"b":"invalid payload","c":["too large","invalid ttl"]},"modified":1616761977.69}"#,
+        )
+        .unwrap();
+        let mut report = UploadReport::default();
+        report.add(response);
+        assert_eq!(report.succeeded, ["a"]);
+        assert_eq!(report.failed["b"], "invalid payload");
+        assert_eq!(report.failed["c"], "too large, invalid ttl");
+
+        report.merge(UploadReport {
+            succeeded: vec!["b".to_string(), "c".to_string()],
+            failed: BTreeMap::new(),
+        });
+        assert_eq!(report.succeeded, ["a", "b", "c"]);
+        assert!(report.failed.is_empty());
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn server_configuration_split_test() {
+        let configuration = ServerConfiguration {
+            max_post_records: 2,
+            max_post_bytes: 25,
+            max_total_records: 4,
+            max_total_bytes: 1000,
+        };
+        assert_eq!(configuration.split(&[]), Vec::<Vec<Range<usize>>>::new());
+        assert_eq!(configuration.split(&[9; 3]), [vec![0..2, 2..3]]);
+        assert_eq!(configuration.split(&[9; 6]), [vec![0..2, 2..4], vec![4..6]]);
+        // Records too large for the POST limit are sent on their own
+        assert_eq!(configuration.split(&[9, 20, 9]), [vec![0..1, 1..2, 2..3]]);
+
+        let configuration = ServerConfiguration {
+            max_total_bytes: 25,
+            ..configuration
+        };
+        assert_eq!(configuration.split(&[9; 3]), [vec![0..2], vec![2..3]]);
+
+        let configuration: ServerConfiguration =
+            serde_json::from_str(r#"{"max_post_records":50,"max_request_bytes":1048576}"#).unwrap();
+        assert_eq!(
+            configuration,
+            ServerConfiguration {
+                max_post_records: 50,
+                ..ServerConfiguration::default()
+            }
+        );
+    }
+
+    #[test]
+    fn bad_request_error_test() {
+        let err: BadRequestError = serde_json::from_str(
+            r#"{"code":429,"errno":114,"error":"Too Many Requests","message":"Client has sent too many requests","retryAfter":30}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            Error::from(err),
+            Error::Unavailable {
+                retry_after: Some(30)
+            }
+        ));
+
+        let err: BadRequestError =
+            serde_json::from_str(r#"{"errno":102,"message":"Unknown account"}"#).unwrap();
+        assert!(matches!(Error::from(err), Error::Fxa { errno: 102, .. }));
+    }
+
+    #[test]
+    fn meta_global_test() {
+        let bso: MetaGlobalBso = serde_json::from_str(
+            r#"{"id":"global","modified":1616761977.69,"payload":"{\"syncID\":\"8Wn-Oa6ScGgm\",\"storageVersion\":5,\"engines\":{\"passwords\":{\"version\":1,\"syncID\":\"N8Qyba0ep6XJ\"}}}"}"#,
+        )
+        .unwrap();
+        let meta_global = bso.payload;
+        assert_eq!(meta_global.engines["passwords"].sync_id, "N8Qyba0ep6XJ");
+        assert!(meta_global.check_writable("passwords").is_ok());
+
+        let meta_global = MetaGlobal {
+            storage_version: 6,
+            ..meta_global
+        };
+        assert!(matches!(
+            meta_global.check_writable("passwords"),
+            Err(Error::UnsupportedStorage(_))
+        ));
+
+        let mut meta_global = MetaGlobal {
+            storage_version: STORAGE_VERSION,
+            ..meta_global
+        };
+        meta_global.engines.get_mut("passwords").unwrap().version = 2;
+        assert!(matches!(
+            meta_global.check_writable("passwords"),
+            Err(Error::UnsupportedStorage(_))
+        ));
+    }
+
+    #[test]
+    fn crypto_keys_test() {
+        let crypto_keys: CryptoKeyRecord = serde_json::from_str(&format!(
+            r#"{{"id":"keys","collection":"crypto","default":["{0}","{0}"],"collections":{{"passwords":["{1}","{1}"]}}}}"#,
+            base64::encode([1u8; 32]),
+            base64::encode([2u8; 32])
+        ))
+        .unwrap();
+        assert_eq!(decode_key_bundle(&crypto_keys.default).unwrap(), [1u8; 64]);
+        assert_eq!(
+            decode_key_bundle(&crypto_keys.collections["passwords"]).unwrap(),
+            [2u8; 64]
+        );
+        assert!(matches!(
+            decode_key_bundle(&crypto_keys.default[..1]),
+            Err(Error::MalformedRecord(_))
+        ));
+    }
+
+    #[test]
+    fn bso_wrong_hmac_key() {
+        let mut key_bundle = [0u8; 64];
+        OsRng.fill_bytes(&mut key_bundle);
+        let login = Login::new(
+            "username",
+            "password",
+            Url::parse("https://github.com").unwrap(),
+        );
+        let bso = BSO::from_object(&login, &key_bundle[0..32], &key_bundle[32..64]).unwrap();
+        assert!(matches!(
+            bso.decrypt_payload(&key_bundle[0..32], &key_bundle[0..32]),
+            Err(Error::Crypto)
+        ));
     }
 }
@@ -0,0 +1,83 @@
+//! Records of the `tabs` collection, in which every client lists its open
+//! tabs, only read by this library.
+
+use serde::{de, Deserialize, Deserializer};
+
+use crate::{BsoObject, Result, SyncClient};
+
+/// The open tabs of a client, whose ID is the ID of the record.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientTabs {
+    id: String,
+    pub client_name: String,
+    #[serde(default)]
+    pub tabs: Vec<Tab>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Tab {
+    pub title: String,
+    /// URLs the tab went through, the current one first
+    pub url_history: Vec<String>,
+    #[serde(default)]
+    pub icon: Option<String>,
+    /// Time the tab was last used, in seconds since the epoch
+    #[serde(default, deserialize_with = "seconds_deserialize")]
+    pub last_used: u64,
+}
+
+/// Some clients send the time as a string.
+fn seconds_deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Seconds {
+        Number(u64),
+        String(String),
+    }
+    match Seconds::deserialize(d)? {
+        Seconds::Number(seconds) => Ok(seconds),
+        Seconds::String(seconds) => seconds.parse().map_err(de::Error::custom),
+    }
+}
+
+impl Tab {
+    /// The URL currently open in the tab.
+    pub fn url(&self) -> Option<&str> {
+        self.url_history.first().map(String::as_str)
+    }
+}
+
+impl BsoObject for ClientTabs {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl SyncClient {
+    /// Download and decrypt the tabs open on every client.
+    pub async fn get_tabs(&self) -> Result<Vec<ClientTabs>> {
+        self.collection("tabs").fetch().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_tabs_test() {
+        let client_tabs: ClientTabs = serde_json::from_str(
+            r#"{"id":"kD2dbJn1Dc8I","clientName":"Firefox on laptop","tabs":[{"title":"Rust","urlHistory":["https://www.rust-lang.org/","https://example.com/"],"icon":"","lastUsed":1626895557},{"title":"Example","urlHistory":["https://example.com/"],"lastUsed":"1626895558"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(client_tabs.id(), "kD2dbJn1Dc8I");
+        assert_eq!(
+            client_tabs.tabs[0].url(),
+            Some("https://www.rust-lang.org/")
+        );
+        assert_eq!(client_tabs.tabs[0].last_used, 1626895557);
+        assert_eq!(client_tabs.tabs[1].last_used, 1626895558);
+    }
+}
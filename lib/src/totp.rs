@@ -0,0 +1,174 @@
+use hmac::{
+    digest::{generic_array::ArrayLength, BlockInput, FixedOutput, Reset, Update},
+    Hmac, Mac, NewMac,
+};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use url::Url;
+
+use crate::{Error, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+/// TOTP parameters, as found in an `otpauth://totp/` URI.
+#[derive(Clone)]
+pub(crate) struct OtpAuth {
+    secret: Vec<u8>,
+    algorithm: Algorithm,
+    digits: u32,
+    period: u64,
+}
+
+impl OtpAuth {
+    pub(crate) fn parse(uri: &str) -> Result<Self> {
+        let malformed = |reason: &str| Error::MalformedRecord(format!("otpauth URI: {}", reason));
+
+        let uri = Url::parse(uri).map_err(|_| malformed("invalid URI"))?;
+        if uri.scheme() != "otpauth" || uri.host_str() != Some("totp") {
+            return Err(malformed("only otpauth://totp/ is supported"));
+        }
+
+        let mut otp_auth = Self {
+            secret: Vec::new(),
+            algorithm: Algorithm::Sha1,
+            digits: 6,
+            period: 30,
+        };
+        for (key, value) in uri.query_pairs() {
+            match key.as_ref() {
+                "secret" => {
+                    otp_auth.secret = base32_decode(&value).ok_or_else(|| malformed("secret"))?
+                }
+                "algorithm" => {
+                    otp_auth.algorithm = match value.to_ascii_uppercase().as_str() {
+                        "SHA1" => Algorithm::Sha1,
+                        "SHA256" => Algorithm::Sha256,
+                        "SHA512" => Algorithm::Sha512,
+                        _ => return Err(malformed("algorithm")),
+                    }
+                }
+                "digits" => {
+                    otp_auth.digits = value
+                        .parse()
+                        .ok()
+                        .filter(|digits| (1..=9).contains(digits))
+                        .ok_or_else(|| malformed("digits"))?
+                }
+                "period" => {
+                    otp_auth.period = value
+                        .parse()
+                        .ok()
+                        .filter(|period| *period > 0)
+                        .ok_or_else(|| malformed("period"))?
+                }
+                _ => {}
+            }
+        }
+        if otp_auth.secret.is_empty() {
+            return Err(malformed("missing secret"));
+        }
+        Ok(otp_auth)
+    }
+
+    /// Generate the code for the given Unix time, in seconds.
+    pub(crate) fn generate(&self, time: u64) -> String {
+        let counter = (time / self.period).to_be_bytes();
+        let hash = match self.algorithm {
+            Algorithm::Sha1 => hmac::<Sha1>(&self.secret, &counter),
+            Algorithm::Sha256 => hmac::<Sha256>(&self.secret, &counter),
+            Algorithm::Sha512 => hmac::<Sha512>(&self.secret, &counter),
+        };
+
+        // Dynamic truncation, see RFC 4226 section 5.3
+        let offset = (hash[hash.len() - 1] & 0xf) as usize;
+        let binary = u32::from_be_bytes([
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]);
+        format!(
+            "{:0width$}",
+            binary % 10u32.pow(self.digits),
+            width = self.digits as usize
+        )
+    }
+}
+
+fn hmac<D>(key: &[u8], message: &[u8]) -> Vec<u8>
+where
+    D: Update + BlockInput + FixedOutput + Reset + Default + Clone,
+    D::BlockSize: ArrayLength<u8>,
+{
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<D>::new_from_slice(key).unwrap();
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Decode RFC 4648 base32, ignoring case, spaces and padding.
+fn base32_decode(input: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in input.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base32_decode_test() {
+        assert_eq!(
+            base32_decode("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap(),
+            b"12345678901234567890"
+        );
+        assert_eq!(base32_decode("mzxw6ytb oi======").unwrap(), b"foobar");
+        assert!(base32_decode("not base32!").is_none());
+    }
+
+    // Test vectors from RFC 6238 appendix B
+    #[test]
+    fn totp_rfc6238_test() {
+        let otp_auth = OtpAuth::parse(
+            "otpauth://totp/FxA:example@riseup.net?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&digits=8",
+        )
+        .unwrap();
+        assert_eq!(otp_auth.generate(59), "94287082");
+        assert_eq!(otp_auth.generate(1111111109), "07081804");
+        assert_eq!(otp_auth.generate(20000000000), "65353130");
+
+        let otp_auth = OtpAuth::parse(
+            "otpauth://totp/FxA?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZA&digits=8&algorithm=SHA256",
+        )
+        .unwrap();
+        assert_eq!(otp_auth.generate(59), "46119246");
+    }
+
+    #[test]
+    fn otpauth_parse_errors_test() {
+        assert!(OtpAuth::parse("otpauth://hotp/FxA?secret=GEZDGNBV").is_err());
+        assert!(OtpAuth::parse("otpauth://totp/FxA").is_err());
+        assert!(OtpAuth::parse("otpauth://totp/FxA?secret=GEZDGNBV&digits=12").is_err());
+    }
+}
@@ -0,0 +1,467 @@
+use pass_fxa_lib::{
+    autofill::{Address, AddressEntry, CreditCard, CreditCardEntry},
+    BsoObject, SyncClient, UploadReport,
+};
+use prs_lib::Plaintext;
+
+use crate::{
+    diff::{pair, MASK},
+    state::State,
+};
+
+/// Property marking the secrets to upload as addresses or credit cards
+pub const TYPE_PROPERTY: &str = "fxa-type";
+
+type AddressField = fn(&mut AddressEntry) -> &mut Option<String>;
+
+/// Properties of an address secret, named like the Firefox fields they set.
+const ADDRESS_FIELDS: &[(&str, AddressField)] = &[
+    ("given-name", |entry| &mut entry.given_name),
+    ("additional-name", |entry| &mut entry.additional_name),
+    ("family-name", |entry| &mut entry.family_name),
+    ("organization", |entry| &mut entry.organization),
+    ("street-address", |entry| &mut entry.street_address),
+    ("address-level3", |entry| &mut entry.address_level3),
+    ("address-level2", |entry| &mut entry.address_level2),
+    ("address-level1", |entry| &mut entry.address_level1),
+    ("postal-code", |entry| &mut entry.postal_code),
+    ("country", |entry| &mut entry.country),
+    ("tel", |entry| &mut entry.tel),
+    ("email", |entry| &mut entry.email),
+];
+
+/// A secret with `fxa-type: address`.
+pub struct LocalAddress {
+    name: String,
+    values: Vec<(AddressField, String)>,
+}
+
+/// A secret with `fxa-type: creditcard`.
+pub struct LocalCreditCard {
+    name: String,
+    /// `cc-number:` property, or the first line
+    number: String,
+    cc_name: Option<String>,
+    exp_month: Option<u32>,
+    exp_year: Option<u32>,
+    cc_type: Option<String>,
+}
+
+/// The secrets marked with `fxa-type:`.
+#[derive(Default)]
+pub struct LocalRecords {
+    pub addresses: Vec<LocalAddress>,
+    pub credit_cards: Vec<LocalCreditCard>,
+}
+
+impl LocalRecords {
+    /// Parse the secret `name`, which has a `fxa-type:` property.
+    pub fn add(&mut self, name: &str, plaintext: &Plaintext) -> Result<(), String> {
+        let property = |property| {
+            plaintext
+                .property(property)
+                .ok()
+                .and_then(|value| value.unsecure_to_str().ok().map(str::to_string))
+        };
+        match property(TYPE_PROPERTY).as_deref() {
+            Some("address") => self.addresses.push(LocalAddress {
+                name: name.to_string(),
+                values: ADDRESS_FIELDS
+                    .iter()
+                    .filter_map(|(property_name, field)| Some((*field, property(property_name)?)))
+                    .collect(),
+            }),
+            Some("creditcard") => {
+                let number = property("cc-number")
+                    .or_else(|| {
+                        plaintext
+                            .first_line()
+                            .ok()
+                            .and_then(|line| line.unsecure_to_str().ok().map(str::to_string))
+                    })
+                    .map(|number| number.replace(&[' ', '-'][..], ""))
+                    .filter(|number| !number.is_empty())
+                    .ok_or_else(|| format!("{} has no card number", name))?;
+                if !number.chars().all(|c| c.is_ascii_digit()) {
+                    return Err(format!("invalid card number in {}", name));
+                }
+                let (mut exp_month, mut exp_year) = match property("cc-exp") {
+                    Some(expiration) => parse_expiration(&expiration)
+                        .ok_or_else(|| format!("invalid expiration date in {}", name))?,
+                    None => (None, None),
+                };
+                if let Some(month) = property("cc-exp-month") {
+                    exp_month = Some(
+                        parse_month(&month)
+                            .ok_or_else(|| format!("invalid expiration month in {}", name))?,
+                    );
+                }
+                if let Some(year) = property("cc-exp-year") {
+                    exp_year =
+                        Some(full_year(year.parse().map_err(|_| {
+                            format!("invalid expiration year in {}", name)
+                        })?));
+                }
+                self.credit_cards.push(LocalCreditCard {
+                    name: name.to_string(),
+                    number,
+                    cc_name: property("cc-name"),
+                    exp_month,
+                    exp_year,
+                    cc_type: property("cc-type"),
+                });
+            }
+            fxa_type => {
+                return Err(format!(
+                    "unknown {} `{}` in {}",
+                    TYPE_PROPERTY,
+                    fxa_type.unwrap_or_default(),
+                    name
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse `MM/YY`, `MM/YYYY` or `YYYY-MM`.
+fn parse_expiration(expiration: &str) -> Option<(Option<u32>, Option<u32>)> {
+    let (first, second) = expiration.trim().split_once(&['/', '-'][..])?;
+    let (month, year) = if first.len() == 4 {
+        (second, first)
+    } else {
+        (first, second)
+    };
+    Some((
+        Some(parse_month(month)?),
+        Some(full_year(year.trim().parse().ok()?)),
+    ))
+}
+
+fn parse_month(month: &str) -> Option<u32> {
+    month
+        .trim()
+        .parse()
+        .ok()
+        .filter(|month| (1..=12).contains(month))
+}
+
+fn full_year(year: u32) -> u32 {
+    if year < 100 {
+        2000 + year
+    } else {
+        year
+    }
+}
+
+/// Set `field` to `value` if given, returning whether it changed.
+fn set<T: PartialEq + Clone>(field: &mut Option<T>, value: Option<&T>) -> bool {
+    match value {
+        Some(value) if field.as_ref() != Some(value) => {
+            *field = Some(value.clone());
+            true
+        }
+        _ => false,
+    }
+}
+
+/// A secret to upload as a record of type `R`.
+trait LocalRecord<R> {
+    fn name(&self) -> &str;
+
+    /// Whether `remote` already holds this secret, when it isn't tracked.
+    fn matches(&self, remote: &R) -> bool;
+
+    /// Set the fields given by the secret, returning whether any changed.
+    fn apply(&self, remote: &mut R) -> bool;
+
+    fn to_record(&self) -> R;
+
+    /// Line describing the record in the dry-run output.
+    fn describe(&self, show_secrets: bool) -> String;
+}
+
+impl LocalRecord<Address> for LocalAddress {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn matches(&self, remote: &Address) -> bool {
+        !self.apply(&mut remote.clone())
+    }
+
+    fn apply(&self, remote: &mut Address) -> bool {
+        let mut changed = false;
+        for (field, value) in &self.values {
+            changed |= set(field(&mut remote.entry), Some(value));
+        }
+        if changed {
+            remote.entry.touch();
+        }
+        changed
+    }
+
+    fn to_record(&self) -> Address {
+        let mut entry = AddressEntry::default();
+        for (field, value) in &self.values {
+            *field(&mut entry) = Some(value.clone());
+        }
+        Address::new(entry)
+    }
+
+    fn describe(&self, _show_secrets: bool) -> String {
+        self.name.clone()
+    }
+}
+
+impl LocalRecord<CreditCard> for LocalCreditCard {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn matches(&self, remote: &CreditCard) -> bool {
+        remote.entry.cc_number() == Some(&self.number)
+    }
+
+    fn apply(&self, remote: &mut CreditCard) -> bool {
+        let entry = &mut remote.entry;
+        let mut changed = entry.cc_number() != Some(&self.number);
+        if changed {
+            entry.set_cc_number(&self.number);
+        }
+        changed |= set(&mut entry.cc_name, self.cc_name.as_ref());
+        changed |= set(&mut entry.cc_exp_month, self.exp_month.as_ref());
+        changed |= set(&mut entry.cc_exp_year, self.exp_year.as_ref());
+        changed |= set(&mut entry.cc_type, self.cc_type.as_ref());
+        if changed {
+            entry.touch();
+        }
+        changed
+    }
+
+    fn to_record(&self) -> CreditCard {
+        let mut entry = CreditCardEntry::default();
+        entry.set_cc_number(&self.number);
+        entry.cc_name = self.cc_name.clone();
+        entry.cc_exp_month = self.exp_month;
+        entry.cc_exp_year = self.exp_year;
+        entry.cc_type = self.cc_type.clone();
+        CreditCard::new(entry)
+    }
+
+    fn describe(&self, show_secrets: bool) -> String {
+        if show_secrets {
+            format!("{} {}", self.name, self.number)
+        } else {
+            let skipped = self.number.chars().count().saturating_sub(4);
+            let last_digits: String = self.number.chars().skip(skipped).collect();
+            format!("{} {}{}", self.name, MASK, last_digits)
+        }
+    }
+}
+
+/// Records to upload for the secrets of one collection.
+struct Changes<'a, L, R> {
+    new: Vec<(&'a L, R)>,
+    changed: Vec<(&'a L, R)>,
+    unchanged: Vec<&'a L>,
+}
+
+impl<'a, L: LocalRecord<R>, R: BsoObject + Clone> Changes<'a, L, R> {
+    /// Pair each secret with the remote record `state` tracks for it, then the
+    /// remaining ones with a record that already holds them.
+    fn new(local_records: &'a [L], remote_records: &[R], state: &State) -> Self {
+        let mut changes = Changes {
+            new: Vec::new(),
+            changed: Vec::new(),
+            unchanged: Vec::new(),
+        };
+        let pairs = pair(
+            local_records,
+            remote_records,
+            &[
+                &|local: &L, remote: &R| state.record_id(local.name()) == Some(remote.id()),
+                &|local: &L, remote: &R| local.matches(remote),
+            ],
+        );
+        for (local, remote) in local_records.iter().zip(pairs) {
+            match remote {
+                Some(remote) => {
+                    let mut updated = remote.clone();
+                    if local.apply(&mut updated) {
+                        changes.changed.push((local, updated));
+                    } else {
+                        changes.unchanged.push(local);
+                    }
+                }
+                None => changes.new.push((local, local.to_record())),
+            }
+        }
+        changes
+    }
+
+    fn print(&self, kind: &str, show_secrets: bool) {
+        let describe = |local: &L| local.describe(show_secrets);
+        print_records(
+            &format!("New {}", kind),
+            self.new.iter().map(|(local, _)| describe(local)),
+        );
+        print_records(
+            &format!("Changed {}", kind),
+            self.changed.iter().map(|(local, _)| describe(local)),
+        );
+        print_records(
+            &format!("Unchanged {}", kind),
+            self.unchanged.iter().map(|local| describe(local)),
+        );
+    }
+
+    /// Records to upload, along with the secret name and record ID to track.
+    fn into_upload(self) -> (Vec<R>, Vec<(&'a str, String)>) {
+        self.new
+            .into_iter()
+            .chain(self.changed)
+            .map(|(local, record)| {
+                let id = record.id().to_string();
+                (record, (local.name(), id))
+            })
+            .unzip()
+    }
+}
+
+fn print_records(title: &str, lines: impl Iterator<Item = String>) {
+    let mut lines: Vec<_> = lines.collect();
+    lines.sort();
+    println!("{} ({}):", title, lines.len());
+    for line in lines {
+        println!("  {}", line);
+    }
+}
+
+/// Upload the addresses and credit cards of the store, updating the records
+/// that were uploaded before.
+pub async fn upload(
+    sync_client: &SyncClient,
+    local_records: &LocalRecords,
+    state: &mut State,
+    dry_run: bool,
+    show_secrets: bool,
+) -> pass_fxa_lib::Result<UploadReport> {
+    let remote_addresses = sync_client.get_addresses().await?;
+    let remote_credit_cards = sync_client.get_credit_cards().await?;
+    let addresses = Changes::new(&local_records.addresses, &remote_addresses, state);
+    let credit_cards = Changes::new(&local_records.credit_cards, &remote_credit_cards, state);
+    if dry_run {
+        addresses.print("addresses", show_secrets);
+        credit_cards.print("credit cards", show_secrets);
+        return Ok(UploadReport::default());
+    }
+
+    let (addresses, mut ids) = addresses.into_upload();
+    let (credit_cards, credit_card_ids) = credit_cards.into_upload();
+    ids.extend(credit_card_ids);
+    println!(
+        "Uploading {} addresses and {} credit cards.",
+        addresses.len(),
+        credit_cards.len()
+    );
+    let mut report = UploadReport::default();
+    if !addresses.is_empty() {
+        report = sync_client.put_addresses(&addresses).await?;
+    }
+    if !credit_cards.is_empty() {
+        let credit_cards_report = sync_client.put_credit_cards(&credit_cards).await?;
+        report.succeeded.extend(credit_cards_report.succeeded);
+        report.failed.extend(credit_cards_report.failed);
+    }
+    for (name, id) in ids {
+        if !report.failed.contains_key(&id) {
+            state.track_record(name, &id);
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_expiration_test() {
+        assert_eq!(parse_expiration("04/27"), Some((Some(4), Some(2027))));
+        assert_eq!(parse_expiration(" 4/2027 "), Some((Some(4), Some(2027))));
+        assert_eq!(parse_expiration("2027-04"), Some((Some(4), Some(2027))));
+        assert_eq!(parse_expiration("13/27"), None);
+        assert_eq!(parse_expiration("00/27"), None);
+        assert_eq!(parse_expiration("0427"), None);
+        assert_eq!(full_year(27), 2027);
+        assert_eq!(full_year(2027), 2027);
+    }
+
+    #[test]
+    fn changes_test() {
+        let mut local_records = LocalRecords::default();
+        for (name, content) in [
+            ("cards/a", "4111111111111111\nfxa-type: creditcard\n"),
+            ("cards/b", "5555555555554444\nfxa-type: creditcard\n"),
+        ] {
+            local_records
+                .add(name, &Plaintext::from(content.to_string()))
+                .unwrap();
+        }
+        let mut entry = CreditCardEntry::default();
+        entry.set_cc_number("4111111111111111");
+        let remote_records = [CreditCard::new(entry)];
+        let mut state = State::default();
+        state.track_record("cards/b", remote_records[0].id());
+
+        // The tracked record goes to its secret even if an earlier one holds it
+        let changes = Changes::new(&local_records.credit_cards, &remote_records, &state);
+        assert_eq!(changes.new.len(), 1);
+        assert_eq!(changes.new[0].0.name, "cards/a");
+        assert_eq!(changes.changed.len(), 1);
+        assert_eq!(changes.changed[0].0.name, "cards/b");
+        assert_eq!(changes.changed[0].1.id(), remote_records[0].id());
+        assert_eq!(
+            changes.changed[0].1.entry.cc_number(),
+            Some("5555555555554444")
+        );
+    }
+
+    #[test]
+    fn credit_card_test() {
+        let mut local_records = LocalRecords::default();
+        local_records
+            .add(
+                "cards/visa",
+                &Plaintext::from(
+                    "4111 1111 1111 1111\nfxa-type: creditcard\ncc-exp: 04/27\ncc-exp-month: 5\n",
+                ),
+            )
+            .unwrap();
+        let credit_card = &local_records.credit_cards[0];
+        assert_eq!(credit_card.number, "4111111111111111");
+        assert_eq!(
+            (credit_card.exp_month, credit_card.exp_year),
+            (Some(5), Some(2027))
+        );
+        assert_eq!(
+            credit_card.describe(false),
+            format!("cards/visa {}1111", MASK)
+        );
+
+        assert!(local_records
+            .add(
+                "cards/month",
+                &Plaintext::from("4111111111111111\nfxa-type: creditcard\ncc-exp-month: 13\n"),
+            )
+            .is_err());
+        assert!(local_records
+            .add(
+                "cards/digits",
+                &Plaintext::from("4111 1111 1111 111٣\nfxa-type: creditcard\n"),
+            )
+            .is_err());
+        assert_eq!(local_records.credit_cards.len(), 1);
+    }
+}
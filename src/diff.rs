@@ -0,0 +1,364 @@
+use pass_fxa_lib::{BsoObject, Login, LoginBuilder, UploadReport};
+use std::collections::HashSet;
+
+use crate::{state::State, LocalLogin};
+
+/// Placeholder printed instead of passwords unless secrets are shown.
+pub const MASK: &str = "********";
+
+/// A login that exists both locally and remotely, with a different password,
+/// username or URL.
+pub struct Change<'a> {
+    pub local: &'a LocalLogin,
+    pub remote: &'a Login,
+}
+
+impl Change<'_> {
+    pub fn password_changed(&self) -> bool {
+        self.remote.password.unsecure() != self.local.password()
+    }
+
+    /// Whether the username, URL or form fields differ.
+    pub fn details_changed(&self) -> bool {
+        !self.local.matches(self.remote) || !self.local.fields_match(self.remote)
+    }
+
+    /// The remote login with the local password, username, URL and form
+    /// fields.
+    pub fn to_login(&self) -> Login {
+        self.to_login_with_password(self.local.password())
+    }
+
+    /// The remote login with the local username, URL and form fields and
+    /// `password`.
+    pub fn to_login_with_password(&self, password: &str) -> Login {
+        self.local
+            .apply(LoginBuilder::from(self.remote.clone()).password(password))
+            .build()
+    }
+}
+
+/// Whether a local item and a remote one are the same.
+pub type Predicate<'p, L, R> = &'p dyn Fn(&L, &R) -> bool;
+
+/// Pair each local item with a remote one, trying the `passes` in order over
+/// all local items before the next one, so that the first passes take
+/// precedence. A remote item is never paired twice.
+pub fn pair<'a, L, R: BsoObject>(
+    local_items: &[L],
+    remote_items: &'a [R],
+    passes: &[Predicate<L, R>],
+) -> Vec<Option<&'a R>> {
+    let mut pairs: Vec<Option<&'a R>> = local_items.iter().map(|_| None).collect();
+    let mut paired = HashSet::new();
+    for predicate in passes {
+        for (local_item, pair) in local_items.iter().zip(pairs.iter_mut()) {
+            if pair.is_some() {
+                continue;
+            }
+            *pair = remote_items.iter().find(|remote_item| {
+                !paired.contains(remote_item.id()) && predicate(local_item, remote_item)
+            });
+            if let Some(remote_item) = pair {
+                paired.insert(remote_item.id());
+            }
+        }
+    }
+    pairs
+}
+
+/// Changes needed to bring the remote logins in line with the local ones.
+pub struct Diff<'a> {
+    /// Local logins that don't exist remotely yet
+    pub new: Vec<Login>,
+    /// Logins whose password, username or URL differ locally and remotely
+    pub changed: Vec<Change<'a>>,
+    /// Remote logins that are identical to the local ones
+    pub unchanged: Vec<&'a Login>,
+    /// Remote logins without a local counterpart
+    pub remote_only: Vec<&'a Login>,
+    /// Secret name and ID of the remote login for each local login
+    pub ids: Vec<(&'a str, String)>,
+}
+
+impl<'a> Diff<'a> {
+    /// Pair local logins with remote ones.
+    ///
+    /// A local login is paired with the remote login `state` tracks for its
+    /// secret, then with a remote login having the same username and URL.
+    /// Failing that, a tracked remote login that lost its secret is taken if
+    /// the password and either the username or the URL are the same, as
+    /// happens when a secret is renamed.
+    pub fn new(local_logins: &'a [LocalLogin], remote_logins: &'a [Login], state: &State) -> Self {
+        let local_names: HashSet<_> = local_logins.iter().map(|login| &login.name[..]).collect();
+        let pairs = pair(
+            local_logins,
+            remote_logins,
+            &[
+                &|local_login: &LocalLogin, remote_login: &Login| {
+                    state.id(&local_login.name) == Some(remote_login.id())
+                },
+                &|local_login: &LocalLogin, remote_login: &Login| local_login.matches(remote_login),
+                &|local_login: &LocalLogin, remote_login: &Login| {
+                    state
+                        .name(remote_login.id())
+                        .is_some_and(|name| !local_names.contains(name))
+                        && remote_login.password.unsecure() == local_login.password()
+                        && (remote_login.username == local_login.username
+                            || remote_login.hostname.origin() == local_login.url.origin())
+                },
+            ],
+        );
+        let paired: HashSet<_> = pairs.iter().flatten().map(|&login| login.id()).collect();
+
+        let mut diff = Diff {
+            new: Vec::new(),
+            changed: Vec::new(),
+            unchanged: Vec::new(),
+            remote_only: Vec::new(),
+            ids: Vec::new(),
+        };
+        for (local_login, pair) in local_logins.iter().zip(pairs) {
+            let id = match pair {
+                Some(remote_login) => {
+                    let change = Change {
+                        local: local_login,
+                        remote: remote_login,
+                    };
+                    if change.password_changed() || change.details_changed() {
+                        diff.changed.push(change);
+                    } else {
+                        diff.unchanged.push(remote_login);
+                    }
+                    remote_login.id().to_string()
+                }
+                None => {
+                    let login = local_login.to_login();
+                    let id = login.id().to_string();
+                    diff.new.push(login);
+                    id
+                }
+            };
+            diff.ids.push((&local_login.name, id));
+        }
+        diff.remote_only = remote_logins
+            .iter()
+            .filter(|remote_login| !paired.contains(remote_login.id()))
+            .collect();
+        diff
+    }
+
+    /// Logins that have to be uploaded to apply the diff.
+    pub fn to_upload(&self) -> Vec<Login> {
+        self.new
+            .iter()
+            .cloned()
+            .chain(self.changed.iter().map(Change::to_login))
+            .collect()
+    }
+
+    /// Remember in `state` the remote login of each local login that `report`
+    /// didn't fail to upload, managing the ones that were new.
+    pub fn track(&self, state: &mut State, report: &UploadReport) {
+        for (name, id) in &self.ids {
+            if !report.failed.contains_key(id) {
+                state.track(name, id);
+            }
+        }
+        for login in &self.new {
+            if !report.failed.contains_key(login.id()) {
+                state.manage(login.id());
+            }
+        }
+    }
+
+    pub fn print(&self, show_secrets: bool) {
+        print_logins("New", self.new.iter(), show_secrets);
+        print_logins(
+            "Changed",
+            self.changed
+                .iter()
+                .map(Change::to_login)
+                .collect::<Vec<_>>()
+                .iter(),
+            show_secrets,
+        );
+        print_logins("Unchanged", self.unchanged.iter().copied(), show_secrets);
+        print_logins(
+            "Remote-only",
+            self.remote_only.iter().copied(),
+            show_secrets,
+        );
+    }
+}
+
+/// Print `logins` under `title`, sorted by hostname and username.
+pub fn print_logins<'a>(title: &str, logins: impl Iterator<Item = &'a Login>, show_secrets: bool) {
+    let mut logins: Vec<_> = logins.collect();
+    logins.sort_by(|a, b| {
+        (a.hostname.as_str(), &a.username).cmp(&(b.hostname.as_str(), &b.username))
+    });
+    println!("{} ({}):", title, logins.len());
+    for login in logins {
+        println!(
+            "  {} {} {}",
+            login.hostname.origin().ascii_serialization(),
+            login.username,
+            if show_secrets {
+                login.password.unsecure()
+            } else {
+                MASK
+            }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prs_lib::{Plaintext, Secret};
+    use url::Url;
+
+    fn local_login(name: &str, content: &str) -> LocalLogin {
+        let secret = Secret {
+            name: name.to_string(),
+            path: format!("{}.gpg", name).into(),
+        };
+        LocalLogin::new(&secret, &Plaintext::from(content.to_string())).unwrap()
+    }
+
+    fn remote_login(username: &str, password: &str, url: &str) -> Login {
+        Login::builder(username, password, Url::parse(url).unwrap()).build()
+    }
+
+    #[test]
+    fn diff_test() {
+        let local_logins = [
+            local_login(
+                "example.com/alice",
+                "hunter2\nurl: https://example.com\nlogin: alice\n",
+            ),
+            local_login(
+                "example.org/bob",
+                "hunter3\nurl: https://example.org\nlogin: bob\n",
+            ),
+            local_login(
+                "example.net/carol",
+                "hunter4\nurl: https://example.net\nlogin: carol\n",
+            ),
+        ];
+        let remote_logins = [
+            remote_login("alice", "hunter2", "https://example.com"),
+            remote_login("bob", "hunter2", "https://example.org"),
+            remote_login("dave", "hunter5", "https://example.io"),
+        ];
+
+        let diff = Diff::new(&local_logins, &remote_logins, &State::default());
+        assert_eq!(diff.new.len(), 1);
+        assert_eq!(diff.new[0].username, "carol");
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].remote.id(), remote_logins[1].id());
+        assert_eq!(diff.unchanged[0].id(), remote_logins[0].id());
+        assert_eq!(diff.remote_only[0].id(), remote_logins[2].id());
+
+        // Only new and changed logins are uploaded, changed ones keeping their ID
+        let to_upload = diff.to_upload();
+        assert_eq!(to_upload.len(), 2);
+        assert_eq!(to_upload[0].id(), diff.new[0].id());
+        assert_eq!(to_upload[1].id(), remote_logins[1].id());
+        assert_eq!(to_upload[1].password.unsecure(), "hunter3");
+    }
+
+    #[test]
+    fn diff_tracked_test() {
+        let local_logins = [local_login(
+            "example.com/alice",
+            "hunter2\nurl: https://example.com\nlogin: alice\n",
+        )];
+        let remote_logins = [
+            remote_login("alice-old", "hunter2", "https://example.com"),
+            remote_login("alice", "hunter2", "https://example.com"),
+        ];
+        let mut state = State::default();
+        state.track("example.com/alice", remote_logins[0].id());
+
+        // The tracked login wins over the one with the same username and URL
+        let diff = Diff::new(&local_logins, &remote_logins, &state);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].remote.id(), remote_logins[0].id());
+        assert_eq!(diff.remote_only[0].id(), remote_logins[1].id());
+        assert_eq!(
+            diff.ids,
+            vec![("example.com/alice", remote_logins[0].id().to_string())]
+        );
+    }
+
+    #[test]
+    fn diff_matching_test() {
+        let local_logins = [
+            local_login(
+                "example.com/alice",
+                "hunter2\nurl: https://example.com/login\nlogin: alice\n",
+            ),
+            local_login("example.org/bob", "hunter3\nurl: https://example.org\n"),
+        ];
+        let remote_logins = [
+            remote_login("alice", "hunter2", "https://example.com"),
+            remote_login("bob", "hunter2", "https://example.org"),
+            remote_login("carol", "hunter2", "https://example.org"),
+        ];
+
+        // The path of the local URL doesn't matter, only its origin
+        let diff = Diff::new(&local_logins, &remote_logins, &State::default());
+        assert_eq!(diff.unchanged[0].id(), remote_logins[0].id());
+        assert_eq!(diff.changed.len(), 1);
+        assert!(diff.changed[0].password_changed());
+        assert!(!diff.changed[0].details_changed());
+        assert_eq!(diff.remote_only.len(), 1);
+        assert_eq!(diff.remote_only[0].id(), remote_logins[2].id());
+        assert!(diff.new.is_empty());
+    }
+
+    #[test]
+    fn diff_renamed_test() {
+        let local_logins = [
+            local_login(
+                "example.com/alice2",
+                "hunter2\nurl: https://example.com\nlogin: alice2\n",
+            ),
+            local_login(
+                "example.org/bob2",
+                "hunter3\nurl: https://example.org\nlogin: bob2\n",
+            ),
+        ];
+        let remote_logins = [
+            remote_login("alice", "hunter2", "https://example.com"),
+            remote_login("bob", "hunter3", "https://example.org"),
+        ];
+        let mut state = State::default();
+        state.track("example.com/alice", remote_logins[0].id());
+        state.track("example.org/bob", remote_logins[1].id());
+
+        // Only the secret that lost its remote login is a rename
+        let renamed = Diff::new(&local_logins[..1], &remote_logins, &state);
+        assert_eq!(renamed.changed.len(), 1);
+        assert_eq!(renamed.changed[0].remote.id(), remote_logins[0].id());
+        assert!(renamed.changed[0].details_changed());
+        assert!(renamed.new.is_empty());
+
+        let with_old = [
+            local_logins[1].clone(),
+            local_login(
+                "example.org/bob",
+                "hunter4\nurl: https://example.org\nlogin: bobby\n",
+            ),
+        ];
+        let diff = Diff::new(&with_old, &remote_logins, &state);
+        assert_eq!(diff.new.len(), 1);
+        assert_eq!(diff.new[0].username, "bob2");
+        assert_eq!(
+            diff.ids[1],
+            ("example.org/bob", remote_logins[1].id().to_string())
+        );
+    }
+}
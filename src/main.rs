@@ -1,10 +1,39 @@
 use log::debug;
 use prs_lib::{crypto::IsContext, Plaintext, Secret, Store};
-use std::{convert::TryFrom, env::VarError, path::Path, process::exit};
+use std::{
+    convert::TryFrom,
+    env::VarError,
+    error::Error,
+    fmt::Display,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::exit,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use structopt::{clap::AppSettings, StructOpt};
 use url::Url;
 
-use pass_fxa_lib::{BsoObject, Login, SyncClient};
+use pass_fxa_lib::{
+    clients::Client, BsoObject, Login, LoginBuilder, LoginCache, Session, SyncClient,
+    SyncClientBuilder, UploadReport,
+};
+
+mod autofill;
+mod diff;
+mod pull;
+mod state;
+mod suggest;
+mod sync;
+
+use autofill::LocalRecords;
+use diff::{print_logins, Diff};
+use state::{State, DEFAULT_STATE_NAME};
+use sync::ConflictPolicy;
+
+const DEFAULT_SESSION_NAME: &str = ".pass-fxa/session";
+const DEFAULT_CACHE_NAME: &str = ".pass-fxa/cache";
+/// Number of times the changes are applied before giving up on conflicts
+const MAX_ATTEMPTS: usize = 3;
 
 const PROPERTY_USER_NAMES: &[&str] = &["login", "username", "user"];
 const PROPERTY_URL_NAMES: &[&str] = &["url", "uri", "website", "site", "link", "launch"];
@@ -29,28 +58,33 @@ impl TryFrom<&str> for Filter {
 
 #[derive(Clone)]
 struct LocalLogin {
+    /// Name of the secret in the store
+    name: String,
+    path: PathBuf,
     password: Plaintext,
     username: String,
     url: Url,
     filter: Option<Filter>,
+    /// `otpauth://` URI, as stored by pass-otp
+    otp_uri: Option<String>,
+    /// `realm:` property, for HTTP authentication
+    http_realm: Option<String>,
+    /// `form-url:` property, as an origin
+    form_submit_url: Option<String>,
+    /// `username-field:` property
+    username_field: Option<String>,
+    /// `password-field:` property
+    password_field: Option<String>,
 }
 
 impl LocalLogin {
-    fn new(prs_lib_plaintext: &Secret, context: &mut prs_lib::crypto::Context) -> Option<Self> {
-        let plaintext = context
-            .decrypt_file(&prs_lib_plaintext.path)
-            .unwrap_or_else(|_| {
-                eprintln!("\nFailed to decrypt {}", prs_lib_plaintext.name);
-                exit(1);
-            });
-        debug!("Decrypted {}", &prs_lib_plaintext.name);
-
+    fn new(prs_lib_plaintext: &Secret, plaintext: &Plaintext) -> Option<Self> {
         // TODO: what to do if no password
         let password = plaintext.first_line().unwrap();
 
         // This is fine to perform as it costs nothing to create a Path
         let name = Path::new(&prs_lib_plaintext.name);
-        let url = match plaintext_property_any(&plaintext, PROPERTY_URL_NAMES) {
+        let url = match plaintext_property_any(plaintext, PROPERTY_URL_NAMES) {
             None => Url::parse(&format!(
                 "https://{}",
                 name.parent().unwrap().file_name()?.to_str().unwrap(),
@@ -59,7 +93,7 @@ impl LocalLogin {
             Some(url_plaintext) => Url::parse(url_plaintext.unsecure_to_str().unwrap()).unwrap(),
         };
 
-        let username = match plaintext_property_any(&plaintext, PROPERTY_USER_NAMES) {
+        let username = match plaintext_property_any(plaintext, PROPERTY_USER_NAMES) {
             Some(login_plaintext) => login_plaintext.unsecure_to_str().unwrap().to_string(),
             // file_name cannot fail as there must be a name
             None => name.file_name().unwrap().to_str().unwrap().to_string(),
@@ -68,34 +102,88 @@ impl LocalLogin {
             Filter::try_from(fxa_setting_plaintext.unsecure_to_str().unwrap())
                 .expect("Unkown setting")
         });
+        let otp_uri = plaintext.unsecure_to_str().ok().and_then(|plaintext| {
+            plaintext
+                .lines()
+                .map(str::trim)
+                .find(|line| line.starts_with("otpauth://"))
+                .map(str::to_string)
+        });
+        let property = |name| {
+            plaintext
+                .property(name)
+                .ok()
+                .map(|value| value.unsecure_to_str().unwrap().to_string())
+        };
+        let form_submit_url = property("form-url").map(|form_url| match Url::parse(&form_url) {
+            Ok(form_url) => form_url.origin().ascii_serialization(),
+            Err(_) => form_url,
+        });
         Some(LocalLogin {
+            name: prs_lib_plaintext.name.clone(),
+            path: prs_lib_plaintext.path.clone(),
             password,
             username,
             url,
             filter,
+            otp_uri,
+            http_realm: property("realm"),
+            form_submit_url,
+            username_field: property("username-field"),
+            password_field: property("password-field"),
         })
     }
 
-    fn to_login(self, online_logins: &Vec<Login>) -> Option<Login> {
-        for online_login in online_logins {
-            if online_login.username == self.username && online_login.hostname == self.url {
-                if online_login.password.unsecure() == self.password.unsecure_to_str().unwrap() {
-                    // If the password is the same, leave unchanged
-                    return None;
-                } else {
-                    // If the password is different, just change that
-                    return Some(
-                        online_login.with_password(self.password.unsecure_to_str().unwrap()),
-                    );
-                }
-            }
+    fn password(&self) -> &str {
+        self.password.unsecure_to_str().unwrap()
+    }
+
+    /// Whether `remote_login` is the remote version of this login.
+    fn matches(&self, remote_login: &Login) -> bool {
+        remote_login.username == self.username
+            && remote_login.hostname.origin() == self.url.origin()
+    }
+
+    /// Whether the form fields given as properties are the same remotely.
+    /// Fields without a property are whatever Firefox recorded.
+    fn fields_match(&self, remote_login: &Login) -> bool {
+        let matches = |local: &Option<String>, remote: Option<&str>| {
+            local.is_none() || local.as_deref() == remote
+        };
+        matches(&self.http_realm, remote_login.http_realm())
+            && matches(&self.form_submit_url, remote_login.form_submit_url())
+            && matches(&self.username_field, Some(remote_login.username_field()))
+            && matches(&self.password_field, Some(remote_login.password_field()))
+    }
+
+    /// Set the username, URL and the form fields given as properties.
+    fn apply(&self, mut builder: LoginBuilder) -> LoginBuilder {
+        builder = builder
+            .username(self.username.clone())
+            .hostname(self.url.clone());
+        if let Some(http_realm) = &self.http_realm {
+            builder = builder.http_realm(http_realm.clone());
+        }
+        if let Some(form_submit_url) = &self.form_submit_url {
+            builder = builder.form_submit_url(form_submit_url.clone());
         }
-        // Create new login if not in remote_logins
-        Some(Login::new(
+        if let Some(username_field) = &self.username_field {
+            builder = builder.username_field(username_field.clone());
+        }
+        if let Some(password_field) = &self.password_field {
+            builder = builder.password_field(password_field.clone());
+        }
+        builder
+    }
+
+    /// A new remote login for this login.
+    fn to_login(&self) -> Login {
+        self.apply(Login::builder(
             &self.username,
-            self.password.unsecure_to_str().unwrap(),
-            self.url,
+            self.password(),
+            self.url.clone(),
         ))
+        .build()
     }
 }
 
@@ -110,60 +198,307 @@ fn get_store() -> Store {
 
 /// Get a property from plaintext by name, in `names` order.
 fn plaintext_property_any(plaintext: &Plaintext, names: &[&str]) -> Option<Plaintext> {
-    names
-        .into_iter()
-        .find_map(|name| plaintext.property(name).ok())
+    names.iter().find_map(|name| plaintext.property(name).ok())
 }
 
-async fn upload(
-    sync_client: SyncClient,
-    exclude: bool,
-    include: bool,
-    local_logins: Vec<LocalLogin>,
-    remote_logins: Vec<Login>,
+/// Decrypt the secret called `name`, if it exists.
+fn read_secret(
+    store: &Store,
+    context: &mut prs_lib::crypto::Context,
+    name: &str,
+) -> Option<String> {
+    let secret = store.find_at(name)?;
+    let plaintext = context.decrypt_file(&secret.path).ok()?;
+    plaintext.unsecure_to_str().ok().map(str::to_string)
+}
+
+/// Encrypt `content` for the store recipients and write it to the secret called `name`.
+fn write_secret(
+    store: &Store,
+    context: &mut prs_lib::crypto::Context,
+    name: &str,
+    content: String,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let path = store.normalize_secret_path(name, None, true)?;
+    context.encrypt_file(&store.recipients()?, Plaintext::from(content), &path)?;
+    Ok(())
+}
+
+/// Ask a yes/no question on the terminal, defaulting to no.
+fn confirm(question: &str) -> bool {
+    matches!(
+        prompt(&format!("{} [y/N] ", question)).as_str(),
+        "y" | "Y" | "yes"
+    )
+}
+
+/// Read a line from the terminal after printing `message`.
+fn prompt(message: &str) -> String {
+    print!("{}", message);
+    exit_on_error(io::stdout().flush());
+    let mut answer = String::new();
+    exit_on_error(io::stdin().read_line(&mut answer));
+    answer.trim().to_string()
+}
+
+/// Print the error and exit the program.
+fn exit_on_error<T>(result: Result<T, impl Display>) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        exit(1);
+    })
+}
+
+/// Write the session of `sync_client` to the store, unless it's the same as
+/// `saved_session_json`.
+fn save_session(
+    store: &Store,
+    context: &mut prs_lib::crypto::Context,
+    session_name: &str,
+    sync_client: &SyncClient,
+    saved_session_json: &mut Option<String>,
 ) {
-    let logins_to_upload: Vec<_> = if exclude || include {
+    let session_json = exit_on_error(serde_json::to_string(&sync_client.session()));
+    if saved_session_json.as_deref() != Some(&session_json) {
+        exit_on_error(write_secret(
+            store,
+            context,
+            session_name,
+            session_json.clone(),
+        ));
+        *saved_session_json = Some(session_json);
+    }
+}
+
+/// Print what the sync server asked to tell the user.
+fn print_server_notices(sync_client: &SyncClient) {
+    if let Some(alert) = sync_client.alert() {
+        eprintln!("Sync server alert: {}", alert);
+    }
+    if let Some(backoff) = sync_client.backoff() {
+        eprintln!(
+            "The sync server is under load, please don't sync again for {} seconds.",
+            backoff
+        );
+    }
+}
+
+/// Print the error along with the sync server notices and exit the program.
+fn exit_on_sync_error<T>(sync_client: &SyncClient, result: pass_fxa_lib::Result<T>) -> T {
+    if result.is_err() {
+        print_server_notices(sync_client);
+    }
+    exit_on_error(result)
+}
+
+/// Only keep the logins selected by the `fxa:` settings.
+fn filter_logins(local_logins: Vec<LocalLogin>, exclude: bool, include: bool) -> Vec<LocalLogin> {
+    if exclude || include {
         local_logins
             .into_iter()
             .filter(|login| include == login.filter.is_some())
-            .filter_map(|local_login| local_login.to_login(&remote_logins))
             .collect()
     } else {
         local_logins
-            .into_iter()
-            .filter_map(|local_login| local_login.to_login(&remote_logins))
-            .collect()
+    }
+}
+
+/// Register pass-fxa in the clients collection and list the other clients.
+async fn register_client(
+    sync_client: &SyncClient,
+    state: &mut State,
+    name: &str,
+    client_type: &str,
+    version: &str,
+    dry_run: bool,
+) -> pass_fxa_lib::Result<UploadReport> {
+    let mut client = match state.client_id() {
+        Some(id) => Client::with_id(id, name, client_type),
+        None => Client::new(name, client_type),
     };
+    client.version = Some(version.to_string());
+    client.os = Some(std::env::consts::OS.to_string());
 
+    let mut other_clients: Vec<_> = sync_client
+        .get_clients()
+        .await?
+        .into_iter()
+        .filter(|other| other.id() != client.id())
+        .collect();
+    other_clients.sort_by(|a, b| {
+        let modified = |client: &Client| client.modified().unwrap_or_default();
+        modified(b).total_cmp(&modified(a))
+    });
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs_f64())
+        .unwrap_or_default();
+    println!("Other clients ({}):", other_clients.len());
+    for other in &other_clients {
+        let minutes = (now - other.modified().unwrap_or_default()).max(0.0) as u64 / 60;
+        let synced = match minutes {
+            0 => "just now".to_string(),
+            minutes if minutes < 60 => format!("{} minutes ago", minutes),
+            minutes if minutes < 48 * 60 => format!("{} hours ago", minutes / 60),
+            minutes => format!("{} days ago", minutes / (24 * 60)),
+        };
+        println!(
+            "  {} ({}), last synced {}",
+            other.name, other.client_type, synced
+        );
+    }
+    if dry_run {
+        return Ok(UploadReport::default());
+    }
+
+    let report = sync_client.register_client(&client).await?;
+    if !report.failed.contains_key(client.id()) {
+        state.set_client_id(client.id());
+    }
+    Ok(report)
+}
+
+/// Download the remote logins, only fetching the changes since the copy cached
+/// in `cache_name` if given.
+async fn fetch_remote_logins(
+    sync_client: &SyncClient,
+    store: &Store,
+    context: &mut prs_lib::crypto::Context,
+    cache_name: Option<&str>,
+) -> pass_fxa_lib::Result<Vec<Login>> {
+    eprintln!("Downloading passwords");
+    let cache_name = match cache_name {
+        Some(cache_name) => cache_name,
+        None => return sync_client.get_logins().await,
+    };
+    let cache_json = read_secret(store, context, cache_name);
+    let mut cache: LoginCache = cache_json
+        .as_deref()
+        .and_then(|cache_json| serde_json::from_str(cache_json).ok())
+        .unwrap_or_default();
+    sync_client.update_logins(&mut cache).await?;
+    let updated_cache_json = exit_on_error(serde_json::to_string(&cache));
+    if cache_json.as_deref() != Some(&updated_cache_json) {
+        exit_on_error(write_secret(store, context, cache_name, updated_cache_json));
+    }
+    Ok(cache.logins())
+}
+
+async fn upload(
+    sync_client: &SyncClient,
+    local_logins: &[LocalLogin],
+    remote_logins: &[Login],
+    state: &mut State,
+    dry_run: bool,
+    show_secrets: bool,
+) -> pass_fxa_lib::Result<UploadReport> {
+    let diff = Diff::new(local_logins, remote_logins, state);
+    if dry_run {
+        diff.print(show_secrets);
+        return Ok(UploadReport::default());
+    }
+
+    let logins_to_upload = diff.to_upload();
     println!("Uploading {} passwords.", logins_to_upload.len());
     debug!("Passwords to upload: {:?}", logins_to_upload);
-    sync_client.put_logins(&logins_to_upload).await;
+    let report = sync_client.put_logins(&logins_to_upload).await?;
+    diff.track(state, &report);
+    Ok(report)
 }
 
-async fn delete(sync_client: SyncClient, local_logins: Vec<LocalLogin>, remote_logins: Vec<Login>) {
-    // IDs which have a matching username, password and URL
+async fn delete(
+    sync_client: &SyncClient,
+    local_logins: &[LocalLogin],
+    remote_logins: &[Login],
+    state: &mut State,
+    dry_run: bool,
+    show_secrets: bool,
+) -> pass_fxa_lib::Result<UploadReport> {
+    // Remote logins which have a matching username, password and URL
     let logins_to_delete: Vec<_> = remote_logins
         .iter()
-        .filter_map(|remote_login| {
-            local_logins
-                .iter()
-                .find(|local_login| {
-                    local_login.username == remote_login.username
-                        && local_login.password.unsecure_to_str().unwrap()
-                            == remote_login.password.unsecure()
-                        && local_login.url == remote_login.hostname
-                })
-                .map(|_| remote_login.id())
+        .filter(|remote_login| {
+            local_logins.iter().any(|local_login| {
+                local_login.matches(remote_login)
+                    && local_login.password() == remote_login.password.unsecure()
+            })
         })
         .collect();
+    if dry_run {
+        print_logins("Delete", logins_to_delete.into_iter(), show_secrets);
+        return Ok(UploadReport::default());
+    }
+
     println!("Deleting {} passwords.", logins_to_delete.len());
-    sync_client.delete_objects(&logins_to_delete).await;
+    let ids_to_delete: Vec<_> = logins_to_delete.iter().map(|login| login.id()).collect();
+    let report = sync_client.delete_objects(&ids_to_delete).await?;
+    state.forget(&report.succeeded);
+    Ok(report)
+}
+
+async fn prune(
+    sync_client: &SyncClient,
+    local_logins: &[LocalLogin],
+    remote_logins: &[Login],
+    state: &mut State,
+    yes: bool,
+    dry_run: bool,
+    show_secrets: bool,
+) -> pass_fxa_lib::Result<UploadReport> {
+    // Remote logins that pass-fxa manages but which don't exist locally anymore
+    let logins_to_prune: Vec<_> = Diff::new(local_logins, remote_logins, state)
+        .remote_only
+        .into_iter()
+        .filter(|remote_login| state.is_managed(remote_login.id()))
+        .collect();
+    if logins_to_prune.is_empty() {
+        println!("Nothing to prune.");
+        return Ok(UploadReport::default());
+    }
+    print_logins("Prune", logins_to_prune.iter().copied(), show_secrets);
+    if dry_run || !(yes || confirm(&format!("Delete {} passwords?", logins_to_prune.len()))) {
+        return Ok(UploadReport::default());
+    }
+
+    println!("Deleting {} passwords.", logins_to_prune.len());
+    let ids_to_delete: Vec<_> = logins_to_prune.iter().map(|login| login.id()).collect();
+    let report = sync_client.delete_objects(&ids_to_delete).await?;
+    state.forget(&report.succeeded);
+    Ok(report)
 }
 
 #[derive(StructOpt)]
 enum Subcommand {
+    /// Upload the secrets marked with `fxa-type:` as addresses and credit cards
+    Autofill,
     /// Delete all remote passwords that are present locally
     Delete,
+    /// Show the differences between the local and remote passwords
+    Diff,
+    /// Write remote passwords that don't exist locally to the store
+    Pull {
+        /// Directory of the store to write the passwords to
+        #[structopt(long)]
+        dir: Option<String>,
+
+        /// Overwrite the secrets that already exist instead of skipping them
+        #[structopt(long)]
+        overwrite: bool,
+    },
+    /// Delete remote passwords managed by pass-fxa that no longer exist locally
+    Prune {
+        /// Don't ask for confirmation
+        #[structopt(long, short)]
+        yes: bool,
+    },
+    /// List the sites of the synced history that have no password
+    Suggest,
+    /// Upload local passwords, pull remote ones and resolve conflicts
+    Sync {
+        /// Which password to keep when they differ locally and remotely
+        #[structopt(long, default_value = "prefer-local", possible_values = ConflictPolicy::VARIANTS)]
+        conflict: ConflictPolicy,
+    },
 }
 
 #[derive(StructOpt)]
@@ -173,6 +508,68 @@ struct Opt {
     #[structopt(long)]
     pass_name: Option<String>,
 
+    /// Print the changes instead of sending them to the server
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Print passwords instead of masking them
+    #[structopt(long)]
+    show_secrets: bool,
+
+    /// Secret in which the FxA session is kept between runs
+    #[structopt(long, default_value = DEFAULT_SESSION_NAME)]
+    session_name: String,
+
+    /// Always log in with the password, without restoring or saving a session
+    #[structopt(long)]
+    no_session: bool,
+
+    /// Secret in which the remote passwords are cached between runs
+    #[structopt(long, default_value = DEFAULT_CACHE_NAME)]
+    cache_name: String,
+
+    /// Download all remote passwords, without using or saving the cache
+    #[structopt(long)]
+    no_cache: bool,
+
+    /// Secret in which pass-fxa keeps track of the passwords it manages
+    #[structopt(long, default_value = DEFAULT_STATE_NAME)]
+    state_name: String,
+
+    /// FxA auth server URL, including the API version
+    #[structopt(long, env = "PASS_FXA_AUTH_URL")]
+    auth_url: Option<Url>,
+
+    /// Token server URL used to get access to the sync server
+    #[structopt(long, env = "PASS_FXA_TOKEN_SERVER_URL")]
+    token_server_url: Option<Url>,
+
+    /// Audience of the BrowserID assertions given to the token server [default: origin of the token server]
+    #[cfg(feature = "browserid")]
+    #[structopt(long, env = "PASS_FXA_AUDIENCE")]
+    audience: Option<String>,
+
+    /// Show pass-fxa among the connected devices of the account, and list the
+    /// other devices
+    #[structopt(long)]
+    register_client: bool,
+
+    /// Name of pass-fxa among the connected devices
+    #[structopt(long, default_value = "pass-fxa")]
+    client_name: String,
+
+    /// Type of device pass-fxa is shown as
+    #[structopt(long, default_value = "desktop", possible_values = &["desktop", "mobile", "tablet", "tv"])]
+    client_type: String,
+
+    /// Version pass-fxa reports among the connected devices
+    #[structopt(long, default_value = env!("CARGO_PKG_VERSION"))]
+    client_version: String,
+
+    /// Use Mozilla's stage servers instead of production
+    #[structopt(long, conflicts_with_all = &["auth-url", "token-server-url"])]
+    stage: bool,
+
     #[structopt(subcommand)]
     subcommand: Option<Subcommand>,
 }
@@ -192,6 +589,7 @@ async fn main() {
     let store = get_store();
 
     let mut local_logins = Vec::new();
+    let mut local_records = LocalRecords::default();
     let mut include = false;
     let mut exclude = false;
 
@@ -199,7 +597,20 @@ async fn main() {
     let secrets_len = secrets.len();
     for (i, secret) in secrets.into_iter().enumerate() {
         eprint!("\r[{}/{}] Local passwords processed", i, secrets_len);
-        let local_login = LocalLogin::new(&secret, &mut pass_context);
+        let plaintext = pass_context.decrypt_file(&secret.path).unwrap_or_else(|_| {
+            eprintln!("\nFailed to decrypt {}", secret.name);
+            exit(1);
+        });
+        debug!("Decrypted {}", &secret.name);
+        if plaintext.property(autofill::TYPE_PROPERTY).is_ok() {
+            if let Some(Subcommand::Autofill) = opt.subcommand {
+                if let Err(err) = local_records.add(&secret.name, &plaintext) {
+                    eprintln!("\nWarning: {}, skipping it", err);
+                }
+            }
+            continue;
+        }
+        let local_login = LocalLogin::new(&secret, &plaintext);
         if let Some(local_login) = local_login {
             if let Some(filter) = &local_login.filter {
                 match filter {
@@ -267,20 +678,224 @@ async fn main() {
 
     let firefox_credentials = firefox_credentials.unwrap();
 
-    let sync_client = SyncClient::new(
-        &firefox_credentials.username,
-        firefox_credentials.password.unsecure_to_str().unwrap(),
-    )
-    .await;
+    let mut sync_client_builder = if opt.stage {
+        SyncClientBuilder::stage()
+    } else {
+        SyncClient::builder()
+    };
+    if let Some(auth_url) = opt.auth_url {
+        sync_client_builder = sync_client_builder.auth_url(auth_url);
+    }
+    if let Some(token_server_url) = opt.token_server_url {
+        sync_client_builder = sync_client_builder.token_server_url(token_server_url);
+    }
+    #[cfg(feature = "browserid")]
+    if let Some(audience) = opt.audience {
+        sync_client_builder = sync_client_builder.audience(audience);
+    }
+    sync_client_builder = match firefox_credentials.otp_uri {
+        Some(ref otp_uri) => exit_on_error(sync_client_builder.totp_uri(otp_uri)),
+        None => sync_client_builder.totp_callback(|| prompt("Two-step authentication code: ")),
+    };
+    sync_client_builder = sync_client_builder
+        .unblock_code_callback(|email| prompt(&format!("A verification code sent to {}: ", email)))
+        .email_confirmation_callback(|email| {
+            println!("Please confirm sign-in by email at {}", email)
+        });
 
-    let remote_logins = sync_client.get_logins().await;
+    let mut saved_session_json = if opt.no_session {
+        None
+    } else {
+        read_secret(&store, &mut pass_context, &opt.session_name)
+    };
+    let saved_session = saved_session_json
+        .as_deref()
+        .and_then(|session_json| serde_json::from_str::<Session>(session_json).ok())
+        .filter(|session| session.email() == firefox_credentials.username);
+
+    let restored_sync_client = match saved_session {
+        Some(session) => match sync_client_builder.clone().restore(session).await {
+            Ok(sync_client) => Some(sync_client),
+            Err(err) => {
+                debug!("Could not restore the saved session: {}", err);
+                None
+            }
+        },
+        None => None,
+    };
+    let sync_client = match restored_sync_client {
+        Some(sync_client) => sync_client,
+        None => exit_on_error(
+            sync_client_builder
+                .login(
+                    &firefox_credentials.username,
+                    firefox_credentials.password.unsecure_to_str().unwrap(),
+                )
+                .await,
+        ),
+    };
 
-    debug!("{:?}", remote_logins);
+    if !opt.no_session {
+        save_session(
+            &store,
+            &mut pass_context,
+            &opt.session_name,
+            &sync_client,
+            &mut saved_session_json,
+        );
+    }
 
-    match opt.subcommand {
-        Some(subcommand) => match subcommand {
-            Subcommand::Delete => delete(sync_client, local_logins, remote_logins).await,
-        },
-        None => upload(sync_client, exclude, include, local_logins, remote_logins).await,
+    let mut state = State::load(&store, &mut pass_context, &opt.state_name);
+    let upload_logins = filter_logins(local_logins.clone(), exclude, include);
+    let cache_name = if opt.no_cache {
+        None
+    } else {
+        Some(opt.cache_name.as_str())
+    };
+
+    // The changes are computed again if the passwords were modified on the
+    // server while they were being applied
+    let mut report = UploadReport::default();
+    for attempt in 1..=MAX_ATTEMPTS {
+        let remote_logins = if let Some(Subcommand::Autofill) = opt.subcommand {
+            Vec::new()
+        } else {
+            exit_on_sync_error(
+                &sync_client,
+                fetch_remote_logins(&sync_client, &store, &mut pass_context, cache_name).await,
+            )
+        };
+        debug!("{:?}", remote_logins);
+
+        let result = match &opt.subcommand {
+            Some(Subcommand::Autofill) => {
+                autofill::upload(
+                    &sync_client,
+                    &local_records,
+                    &mut state,
+                    opt.dry_run,
+                    opt.show_secrets,
+                )
+                .await
+            }
+            Some(Subcommand::Delete) => {
+                delete(
+                    &sync_client,
+                    &local_logins,
+                    &remote_logins,
+                    &mut state,
+                    opt.dry_run,
+                    opt.show_secrets,
+                )
+                .await
+            }
+            Some(Subcommand::Prune { yes }) => {
+                prune(
+                    &sync_client,
+                    &local_logins,
+                    &remote_logins,
+                    &mut state,
+                    *yes,
+                    opt.dry_run,
+                    opt.show_secrets,
+                )
+                .await
+            }
+            Some(Subcommand::Diff) => {
+                Diff::new(&upload_logins, &remote_logins, &state).print(opt.show_secrets);
+                Ok(UploadReport::default())
+            }
+            Some(Subcommand::Pull { dir, overwrite }) => {
+                pull::pull(
+                    &store,
+                    &mut pass_context,
+                    &local_logins,
+                    &remote_logins,
+                    &mut state,
+                    dir.as_deref(),
+                    *overwrite,
+                    opt.dry_run,
+                );
+                Ok(UploadReport::default())
+            }
+            Some(Subcommand::Suggest) => {
+                suggest::suggest(&sync_client, &local_logins, &remote_logins)
+                    .await
+                    .map(|()| UploadReport::default())
+            }
+            Some(Subcommand::Sync { conflict }) => {
+                sync::sync(
+                    &sync_client,
+                    &store,
+                    &mut pass_context,
+                    &local_logins,
+                    &upload_logins,
+                    &remote_logins,
+                    &mut state,
+                    *conflict,
+                    opt.dry_run,
+                    opt.show_secrets,
+                )
+                .await
+            }
+            None => {
+                upload(
+                    &sync_client,
+                    &upload_logins,
+                    &remote_logins,
+                    &mut state,
+                    opt.dry_run,
+                    opt.show_secrets,
+                )
+                .await
+            }
+        };
+        match result {
+            Err(pass_fxa_lib::Error::Conflict) if attempt < MAX_ATTEMPTS => {
+                eprintln!("The passwords were modified on the server meanwhile, trying again.");
+            }
+            result => {
+                report = exit_on_sync_error(&sync_client, result);
+                break;
+            }
+        }
+    }
+
+    if opt.register_client {
+        let client_report = exit_on_sync_error(
+            &sync_client,
+            register_client(
+                &sync_client,
+                &mut state,
+                &opt.client_name,
+                &opt.client_type,
+                &opt.client_version,
+                opt.dry_run,
+            )
+            .await,
+        );
+        report.failed.extend(client_report.failed);
+    }
+
+    if !opt.dry_run {
+        state.save(&store, &mut pass_context, &opt.state_name);
+    }
+    // The sync server credentials may have been renewed
+    if !opt.no_session {
+        save_session(
+            &store,
+            &mut pass_context,
+            &opt.session_name,
+            &sync_client,
+            &mut saved_session_json,
+        );
+    }
+
+    print_server_notices(&sync_client);
+    if !report.failed.is_empty() {
+        for (id, reason) in &report.failed {
+            eprintln!("Error: the server rejected {}: {}", id, reason);
+        }
+        exit(1);
     }
 }
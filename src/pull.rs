@@ -0,0 +1,127 @@
+use pass_fxa_lib::{BsoObject, Login};
+use prs_lib::Store;
+
+use crate::{diff::Diff, exit_on_error, state::State, write_secret, LocalLogin};
+
+/// Name of the secret a remote login is written to: `[<dir>/]<host>/<username>`.
+fn secret_name(login: &Login, dir: Option<&str>) -> Option<String> {
+    let host = login.hostname.host_str()?;
+    if login.username.is_empty() {
+        return None;
+    }
+    // Slashes would create extra directories
+    let name = format!("{}/{}", host, login.username.replace('/', "_"));
+    Some(match dir {
+        Some(dir) => format!("{}/{}", dir.trim_end_matches('/'), name),
+        None => name,
+    })
+}
+
+/// Multi-line secret content that `LocalLogin::new` parses back into `login`.
+fn secret_content(login: &Login) -> String {
+    let mut content = format!(
+        "{}\nurl: {}\nlogin: {}\n",
+        login.password.unsecure(),
+        login.hostname.origin().ascii_serialization(),
+        login.username
+    );
+    if let Some(http_realm) = login.http_realm() {
+        content.push_str(&format!("realm: {}\n", http_realm));
+    }
+    content
+}
+
+/// Write `login` to the store, returns the name of the secret if it was written.
+pub fn write_login(
+    store: &Store,
+    context: &mut prs_lib::crypto::Context,
+    login: &Login,
+    dir: Option<&str>,
+    overwrite: bool,
+    dry_run: bool,
+) -> Option<String> {
+    let name = match secret_name(login, dir) {
+        Some(name) => name,
+        None => {
+            eprintln!(
+                "Skipping login without username for {}",
+                login.hostname.origin().ascii_serialization()
+            );
+            return None;
+        }
+    };
+    if !overwrite && store.find_at(&name).is_some() {
+        println!("Skipping {}, it already exists", name);
+        return None;
+    }
+    if dry_run {
+        println!("Would write {}", name);
+        return None;
+    }
+    exit_on_error(write_secret(store, context, &name, secret_content(login)));
+    println!("Wrote {}", name);
+    Some(name)
+}
+
+/// Write the remote logins that don't exist locally to the store.
+#[allow(clippy::too_many_arguments)]
+pub fn pull(
+    store: &Store,
+    context: &mut prs_lib::crypto::Context,
+    local_logins: &[LocalLogin],
+    remote_logins: &[Login],
+    state: &mut State,
+    dir: Option<&str>,
+    overwrite: bool,
+    dry_run: bool,
+) {
+    let mut written = 0;
+    for login in Diff::new(local_logins, remote_logins, state).remote_only {
+        if let Some(name) = write_login(store, context, login, dir, overwrite, dry_run) {
+            state.track(&name, login.id());
+            written += 1;
+        }
+    }
+    if !dry_run {
+        println!("Pulled {} passwords.", written);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use url::Url;
+
+    #[test]
+    fn secret_name_test() {
+        let url = Url::parse("https://www.example.com/login").unwrap();
+        let login = Login::builder("alice/work", "hunter2", url.clone()).build();
+        assert_eq!(
+            secret_name(&login, None).as_deref(),
+            Some("www.example.com/alice_work")
+        );
+        assert_eq!(
+            secret_name(&login, Some("firefox/")).as_deref(),
+            Some("firefox/www.example.com/alice_work")
+        );
+        let anonymous = Login::builder("", "hunter2", url).build();
+        assert_eq!(secret_name(&anonymous, None), None);
+    }
+
+    #[test]
+    fn secret_content_test() {
+        let url = Url::parse("https://www.example.com/login").unwrap();
+        let login = Login::builder("alice", "hunter2", url.clone()).build();
+        assert_eq!(
+            secret_content(&login),
+            "hunter2\nurl: https://www.example.com\nlogin: alice\n"
+        );
+        let http_login = Login::builder("alice", "hunter2", url)
+            .http_realm("Intranet")
+            .build();
+        assert_eq!(
+            secret_content(&http_login),
+            "hunter2\nurl: https://www.example.com\nlogin: alice\nrealm: Intranet\n"
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use prs_lib::Store;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{exit_on_error, read_secret, write_secret};
+
+pub const DEFAULT_STATE_NAME: &str = ".pass-fxa/state";
+
+/// What pass-fxa remembers between runs, kept encrypted in the store.
+#[derive(Serialize, Deserialize, Default)]
+pub struct State {
+    /// IDs of the remote logins pass-fxa uploaded as new, the only ones it
+    /// prunes
+    #[serde(default)]
+    managed: BTreeSet<String>,
+
+    /// ID of the remote login each secret corresponds to, by secret name
+    #[serde(default)]
+    entries: BTreeMap<String, String>,
+
+    /// ID of the address or credit card each secret corresponds to, by secret
+    /// name, kept apart from the logins as these are never pruned
+    #[serde(default)]
+    records: BTreeMap<String, String>,
+
+    /// ID of the record of pass-fxa in the clients collection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+
+    /// Serialized state as it was loaded, to only write it back when changed
+    #[serde(skip)]
+    loaded: Option<String>,
+}
+
+impl State {
+    pub fn load(store: &Store, context: &mut prs_lib::crypto::Context, name: &str) -> Self {
+        match read_secret(store, context, name) {
+            Some(json) => State {
+                loaded: Some(json.clone()),
+                ..exit_on_error(serde_json::from_str(&json))
+            },
+            None => State::default(),
+        }
+    }
+
+    pub fn save(&self, store: &Store, context: &mut prs_lib::crypto::Context, name: &str) {
+        let json = exit_on_error(serde_json::to_string(self));
+        if self.loaded.as_ref() != Some(&json) {
+            exit_on_error(write_secret(store, context, name, json));
+        }
+    }
+
+    pub fn is_managed(&self, id: &str) -> bool {
+        self.managed.contains(id)
+    }
+
+    /// ID of the remote login the secret `name` corresponds to.
+    pub fn id(&self, name: &str) -> Option<&str> {
+        self.entries.get(name).map(String::as_str)
+    }
+
+    /// Name of the secret the remote login `id` corresponds to.
+    pub fn name(&self, id: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(_, entry_id)| *entry_id == id)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Remember that the secret `name` corresponds to the remote login `id`.
+    pub fn track(&mut self, name: &str, id: &str) {
+        // The secret may have been renamed
+        self.entries.retain(|_, entry_id| entry_id != id);
+        self.entries.insert(name.to_string(), id.to_string());
+    }
+
+    /// Remember that pass-fxa uploaded the remote login `id`, so that it's
+    /// pruned once its secret is removed.
+    pub fn manage(&mut self, id: &str) {
+        self.managed.insert(id.to_string());
+    }
+
+    /// ID of the address or credit card the secret `name` corresponds to.
+    pub fn record_id(&self, name: &str) -> Option<&str> {
+        self.records.get(name).map(String::as_str)
+    }
+
+    /// Remember that the secret `name` corresponds to the address or credit
+    /// card `id`.
+    pub fn track_record(&mut self, name: &str, id: &str) {
+        self.records.retain(|_, record_id| record_id != id);
+        self.records.insert(name.to_string(), id.to_string());
+    }
+
+    pub fn client_id(&self) -> Option<&str> {
+        self.client_id.as_deref()
+    }
+
+    pub fn set_client_id(&mut self, client_id: &str) {
+        self.client_id = Some(client_id.to_string());
+    }
+
+    /// Forget about deleted remote logins.
+    pub fn forget(&mut self, ids: &[String]) {
+        for id in ids {
+            self.managed.remove(id);
+        }
+        self.entries.retain(|_, entry_id| !ids.contains(entry_id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_test() {
+        let mut state = State::default();
+        state.track("example.com/alice", "5fAxGX8Wc9zq");
+        state.manage("5fAxGX8Wc9zq");
+        state.track("example.org/bob", "kD2dbJn1Dc8I");
+        assert_eq!(state.id("example.com/alice"), Some("5fAxGX8Wc9zq"));
+        assert_eq!(state.name("kD2dbJn1Dc8I"), Some("example.org/bob"));
+        assert!(state.is_managed("5fAxGX8Wc9zq"));
+        assert!(!state.is_managed("kD2dbJn1Dc8I"));
+
+        // Renamed secret
+        state.track("example.com/alice2", "5fAxGX8Wc9zq");
+        assert_eq!(state.id("example.com/alice"), None);
+        assert_eq!(state.name("5fAxGX8Wc9zq"), Some("example.com/alice2"));
+        assert!(state.is_managed("5fAxGX8Wc9zq"));
+
+        state.forget(&["5fAxGX8Wc9zq".to_string()]);
+        assert_eq!(state.id("example.com/alice2"), None);
+        assert!(!state.is_managed("5fAxGX8Wc9zq"));
+        assert_eq!(state.id("example.org/bob"), Some("kD2dbJn1Dc8I"));
+    }
+}
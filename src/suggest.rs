@@ -0,0 +1,50 @@
+use pass_fxa_lib::{Login, SyncClient};
+use std::collections::{BTreeMap, HashSet};
+use url::Url;
+
+use crate::LocalLogin;
+
+/// Host without the `www.` prefix, which sites usually redirect to or from.
+fn site(host: &str) -> &str {
+    host.strip_prefix("www.").unwrap_or(host)
+}
+
+/// Print the hosts of the synced history that have neither a secret nor a
+/// remote login, the most visited first.
+pub async fn suggest(
+    sync_client: &SyncClient,
+    local_logins: &[LocalLogin],
+    remote_logins: &[Login],
+) -> pass_fxa_lib::Result<()> {
+    let known: HashSet<_> = local_logins
+        .iter()
+        .filter_map(|login| login.url.host_str())
+        .chain(
+            remote_logins
+                .iter()
+                .filter_map(|login| login.hostname.host_str()),
+        )
+        .map(site)
+        .collect();
+
+    let mut visits = BTreeMap::new();
+    for record in sync_client.get_history().await? {
+        let url = match Url::parse(&record.uri) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => url,
+            _ => continue,
+        };
+        if let Some(host) = url.host_str() {
+            if !known.contains(site(host)) {
+                *visits.entry(site(host).to_string()).or_insert(0) += record.visits.len();
+            }
+        }
+    }
+
+    let mut sites: Vec<_> = visits.into_iter().collect();
+    sites.sort_by(|(_, a), (_, b)| b.cmp(a));
+    println!("Sites without a password ({}):", sites.len());
+    for (site, visits) in sites {
+        println!("  {} ({} visits)", site, visits);
+    }
+    Ok(())
+}
@@ -0,0 +1,325 @@
+use pass_fxa_lib::{BsoObject, Login, SyncClient, UploadReport};
+use prs_lib::{crypto::IsContext, Plaintext, Store};
+use std::{
+    error::Error,
+    fs,
+    io::{self, Write},
+    path::Path,
+    process::Command,
+    str::FromStr,
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    diff::{print_logins, Change, Diff},
+    exit_on_error, pull,
+    state::State,
+    write_secret, LocalLogin,
+};
+
+/// How to resolve a login whose password differs locally and remotely.
+#[derive(Clone, Copy)]
+pub enum ConflictPolicy {
+    PreferLocal,
+    PreferRemote,
+    NewestWins,
+    Interactive,
+}
+
+impl ConflictPolicy {
+    pub const VARIANTS: &'static [&'static str] = &[
+        "prefer-local",
+        "prefer-remote",
+        "newest-wins",
+        "interactive",
+    ];
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "prefer-local" => Ok(Self::PreferLocal),
+            "prefer-remote" => Ok(Self::PreferRemote),
+            "newest-wins" => Ok(Self::NewestWins),
+            "interactive" => Ok(Self::Interactive),
+            _ => Err(format!("unknown conflict policy `{}`", value)),
+        }
+    }
+}
+
+enum Resolution {
+    Local,
+    Remote,
+    Skip,
+}
+
+/// Commit time of `path` in milliseconds since the Unix epoch, if the store is
+/// a git repository and `path` has no uncommitted changes.
+fn git_commit_time(store: &Store, path: &Path) -> Option<u64> {
+    if !store.root.join(".git").exists() {
+        return None;
+    }
+    let git = || {
+        let mut command = Command::new("git");
+        command.arg("-C").arg(&store.root);
+        command
+    };
+
+    let status = git()
+        .args(["status", "--porcelain", "--"])
+        .arg(path)
+        .output()
+        .ok()?;
+    if !status.status.success() || !status.stdout.is_empty() {
+        return None;
+    }
+    let log = git()
+        .args(["log", "-1", "--format=%ct", "--"])
+        .arg(path)
+        .output()
+        .ok()?;
+    let seconds: u64 = String::from_utf8(log.stdout).ok()?.trim().parse().ok()?;
+    Some(seconds * 1000)
+}
+
+/// When the local password was last changed, in milliseconds since the Unix epoch.
+fn local_modification_time(store: &Store, local_login: &LocalLogin) -> Option<u64> {
+    git_commit_time(store, &local_login.path).or_else(|| {
+        let modified = fs::metadata(&local_login.path).ok()?.modified().ok()?;
+        Some(modified.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64)
+    })
+}
+
+fn prompt(change: &Change, show_secrets: bool) -> Resolution {
+    let (local_password, remote_password) = if show_secrets {
+        (change.local.password(), change.remote.password.unsecure())
+    } else {
+        ("********", "********")
+    };
+    println!(
+        "Conflict for {} ({}):\n  local:  {}\n  remote: {}",
+        change.local.name,
+        change.remote.hostname.origin().ascii_serialization(),
+        local_password,
+        remote_password
+    );
+    loop {
+        print!("Keep [l]ocal, [r]emote or [s]kip? ");
+        exit_on_error(io::stdout().flush());
+        let mut answer = String::new();
+        if exit_on_error(io::stdin().read_line(&mut answer)) == 0 {
+            return Resolution::Skip;
+        }
+        match answer.trim() {
+            "l" | "local" => return Resolution::Local,
+            "r" | "remote" => return Resolution::Remote,
+            "s" | "skip" => return Resolution::Skip,
+            _ => {}
+        }
+    }
+}
+
+fn resolve(
+    policy: ConflictPolicy,
+    change: &Change,
+    store: &Store,
+    show_secrets: bool,
+) -> Resolution {
+    match policy {
+        ConflictPolicy::PreferLocal => Resolution::Local,
+        ConflictPolicy::PreferRemote => Resolution::Remote,
+        ConflictPolicy::NewestWins => {
+            match (
+                local_modification_time(store, change.local),
+                change.remote.time_password_changed(),
+            ) {
+                (Some(local_time), Some(remote_time)) if remote_time > local_time => {
+                    Resolution::Remote
+                }
+                // The store is the reference when in doubt
+                _ => Resolution::Local,
+            }
+        }
+        ConflictPolicy::Interactive => prompt(change, show_secrets),
+    }
+}
+
+/// Replace the password of `local_login`, keeping the rest of the secret.
+fn update_local_password(
+    store: &Store,
+    context: &mut prs_lib::crypto::Context,
+    local_login: &LocalLogin,
+    password: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let rest = context
+        .decrypt_file(&local_login.path)?
+        .except_first_line()?;
+    let mut plaintext = Plaintext::from(password);
+    if !rest.is_empty() {
+        plaintext.append(rest, true);
+    }
+    write_secret(
+        store,
+        context,
+        &local_login.name,
+        plaintext.unsecure_to_str()?.to_string(),
+    )
+}
+
+/// Push local-only and changed logins, pull remote-only logins and resolve
+/// password conflicts with `policy`.
+///
+/// `upload_logins` are the local logins that may be uploaded, while all
+/// `local_logins` are taken into account when looking for remote-only logins.
+/// The store is only written to once the upload succeeded, so that the sync
+/// can be retried on conflicts.
+#[allow(clippy::too_many_arguments)]
+pub async fn sync(
+    sync_client: &SyncClient,
+    store: &Store,
+    context: &mut prs_lib::crypto::Context,
+    local_logins: &[LocalLogin],
+    upload_logins: &[LocalLogin],
+    remote_logins: &[Login],
+    state: &mut State,
+    policy: ConflictPolicy,
+    dry_run: bool,
+    show_secrets: bool,
+) -> pass_fxa_lib::Result<UploadReport> {
+    let diff = Diff::new(upload_logins, remote_logins, state);
+
+    let mut logins_to_upload = diff.new.clone();
+    let mut local_updates = Vec::new();
+    for change in &diff.changed {
+        if !change.password_changed() {
+            logins_to_upload.push(change.to_login());
+            continue;
+        }
+        let policy = if dry_run {
+            // Don't prompt for changes that won't be applied
+            match policy {
+                ConflictPolicy::Interactive => {
+                    println!("Would ask what to do with {}", change.local.name);
+                    continue;
+                }
+                policy => policy,
+            }
+        } else {
+            policy
+        };
+        match resolve(policy, change, store, show_secrets) {
+            Resolution::Local => logins_to_upload.push(change.to_login()),
+            Resolution::Remote if dry_run => {
+                println!("Would update {}", change.local.name)
+            }
+            Resolution::Remote => {
+                local_updates.push(change);
+                if change.details_changed() {
+                    logins_to_upload
+                        .push(change.to_login_with_password(change.remote.password.unsecure()));
+                }
+            }
+            Resolution::Skip => println!("Skipped {}", change.local.name),
+        }
+    }
+
+    let mut report = UploadReport::default();
+    if dry_run {
+        print_logins("Upload", logins_to_upload.iter(), show_secrets);
+    } else {
+        println!("Uploading {} passwords.", logins_to_upload.len());
+        report = sync_client.put_logins(&logins_to_upload).await?;
+        diff.track(state, &report);
+    }
+
+    for change in local_updates {
+        exit_on_error(update_local_password(
+            store,
+            context,
+            change.local,
+            change.remote.password.unsecure(),
+        ));
+        println!("Updated {}", change.local.name);
+    }
+    for login in Diff::new(local_logins, remote_logins, state).remote_only {
+        if let Some(name) = pull::write_login(store, context, login, None, false, dry_run) {
+            state.track(&name, login.id());
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prs_lib::Secret;
+    use std::{path::PathBuf, process};
+    use url::Url;
+
+    #[test]
+    fn conflict_policy_test() {
+        for name in ConflictPolicy::VARIANTS {
+            assert!(name.parse::<ConflictPolicy>().is_ok());
+        }
+        assert!(matches!(
+            "newest-wins".parse(),
+            Ok(ConflictPolicy::NewestWins)
+        ));
+        assert!("newest".parse::<ConflictPolicy>().is_err());
+    }
+
+    #[test]
+    fn resolve_test() {
+        let root: PathBuf = std::env::temp_dir().join(format!("pass-fxa-test-{}", process::id()));
+        fs::create_dir_all(root.join("example.com")).unwrap();
+        let path = root.join("example.com/alice.gpg");
+        fs::write(&path, "").unwrap();
+        let store = Store::open(root.to_str().unwrap()).unwrap();
+
+        let secret = Secret::in_root(&root, path);
+        let local = LocalLogin::new(
+            &secret,
+            &Plaintext::from("hunter2\nurl: https://example.com\n"),
+        )
+        .unwrap();
+        let url = Url::parse("https://example.com").unwrap();
+        let resolve_with = |policy, remote_time: Option<u64>| {
+            let mut builder = Login::builder("alice", "hunter3", url.clone());
+            if let Some(remote_time) = remote_time {
+                builder = builder.time_password_changed(remote_time);
+            }
+            let remote = builder.build();
+            let change = Change {
+                local: &local,
+                remote: &remote,
+            };
+            resolve(policy, &change, &store, false)
+        };
+
+        assert!(matches!(
+            resolve_with(ConflictPolicy::PreferLocal, Some(u64::MAX)),
+            Resolution::Local
+        ));
+        assert!(matches!(
+            resolve_with(ConflictPolicy::PreferRemote, Some(0)),
+            Resolution::Remote
+        ));
+        assert!(matches!(
+            resolve_with(ConflictPolicy::NewestWins, Some(u64::MAX)),
+            Resolution::Remote
+        ));
+        assert!(matches!(
+            resolve_with(ConflictPolicy::NewestWins, Some(0)),
+            Resolution::Local
+        ));
+
+        fs::remove_dir_all(&root).unwrap();
+        // Without a modification time, the local password wins
+        assert!(matches!(
+            resolve_with(ConflictPolicy::NewestWins, Some(u64::MAX)),
+            Resolution::Local
+        ));
+    }
+}